@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 use std::env;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Once, OnceLock};
+
+use serenity::client::Context;
 
 use crate::history::History;
 use crate::openai::build_json_client;
@@ -11,6 +13,11 @@ pub struct Bot {
     pub client: reqwest::Client,
     pub model: String,
     pub user_limits: Arc<Mutex<HashMap<u64, (i64, u64)>>>,
+    /// Guards the background tasks `ready` starts, so they only start once
+    pub background: Arc<Once>,
+    /// The first context `ready` gets, for the shutdown handler `main`
+    /// installs before there is one
+    pub context: Arc<OnceLock<Context>>,
 }
 
 impl Bot {
@@ -27,6 +34,8 @@ impl Bot {
             client,
             model,
             user_limits: Arc::new(Mutex::new(HashMap::new())),
+            background: Arc::new(Once::new()),
+            context: Arc::new(OnceLock::new()),
         }
     }
 }
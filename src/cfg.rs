@@ -1,8 +1,28 @@
 pub const LOG_FILE: &str = "output.log";
 
+pub const DATA_DIR: &str = "data";
+
 pub const BOT_ID: u64 = 844335104255590431;
 
 pub const SYS_PROMPT: &str = "You will be receiving messages in the format: 'username: message'.
 If you see a username 'adam' in the conversation history, that was you.
 Do not respond in the this format, only respond with responses.
 If you're unable to respond to something, respond in an ominous manner.";
+
+/// Used for session recaps unless `RECAP_PROMPT_FILE` points at a custom template.
+/// `{notes}` is replaced with the (summarized) session transcript.
+pub const RECAP_PROMPT: &str = "You are the narrator for a tabletop roleplaying group.
+Using the session notes below, write a short \"Previously on...\" recap in the voice of a storyteller.
+Then list the following sections in Markdown, leaving out any that are empty:
+## Key events
+## Decisions
+## Named entities (people, places, items, factions)
+## Action items
+
+Session notes:
+{notes}";
+
+pub const CHUNK_SUMMARY_PROMPT: &str =
+    "You will be given part of a voice session transcript in the format '[time] speaker: text'.
+Summarize it as concise notes in chronological order.
+Keep every event, decision, name (people, places, items, factions) and open task or plan.";
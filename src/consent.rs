@@ -0,0 +1,190 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use dashmap::DashSet;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serenity::all::GuildId;
+use serenity::client::Context;
+use serenity::framework::standard::macros::command;
+use serenity::framework::standard::{Args, CommandResult};
+use serenity::gateway::ActivityData;
+use serenity::model::channel::Message;
+use tokio::sync::Mutex;
+
+use crate::session::get_sessions;
+use crate::state::ConsentKey;
+use crate::store::{get_store, Store};
+
+const TABLE: &str = "consent";
+const OPTED_OUT_KEY: &str = "opted_out";
+const AUDIT_TABLE: &str = "consent_audit";
+
+/// Posted in the channel the bot was asked to join from.
+pub const ANNOUNCEMENT: &str = "🔴 **This voice channel is now being recorded and transcribed.** \
+    Staying in the channel means you're OK with that. Use `~optout` to have your voice skipped \
+    from now on, in every server, or `~optin` to undo it.";
+
+/// Spoken in the voice channel when there's text to speech.
+pub const SPOKEN_ANNOUNCEMENT: &str = "Heads up, this channel is now being recorded. \
+    Type tilde opt out in chat if you don't want to be.";
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsentAction {
+    OptedOut,
+    OptedIn,
+    /// First recorded in a session after the announcement, which counts as
+    /// consenting
+    Recorded,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AuditEntry {
+    at: DateTime<Utc>,
+    guild_id: Option<GuildId>,
+    action: ConsentAction,
+}
+
+/// Who has asked not to be recorded, kept in memory since it's checked for
+/// every voice packet.
+pub struct Consent {
+    opted_out: DashSet<u64>,
+    /// Held while opt-outs or the audit log are changed and saved, so an
+    /// older snapshot can't be saved over a newer one
+    saving: Mutex<()>,
+}
+
+impl Consent {
+    pub fn load(store: &Store) -> Self {
+        let opted_out: BTreeSet<u64> = store.get(TABLE, OPTED_OUT_KEY).unwrap_or_default();
+        info!("{} users opted out of recording", opted_out.len());
+
+        Self {
+            opted_out: opted_out.into_iter().collect(),
+            saving: Mutex::new(()),
+        }
+    }
+
+    pub fn is_opted_out(&self, user_id: u64) -> bool {
+        self.opted_out.contains(&user_id)
+    }
+
+    /// Returns whether anything changed.
+    async fn set_opted_out(&self, store: &Store, user_id: u64, opted_out: bool) -> bool {
+        let _guard = self.saving.lock().await;
+
+        let changed = if opted_out {
+            self.opted_out.insert(user_id)
+        } else {
+            self.opted_out.remove(&user_id).is_some()
+        };
+
+        if changed {
+            let all: BTreeSet<u64> = self.opted_out.iter().map(|id| *id).collect();
+            if let Err(e) = store.put(TABLE, OPTED_OUT_KEY, &all) {
+                error!("Failed to save recording opt-outs: {:?}", e);
+            }
+        }
+
+        changed
+    }
+}
+
+async fn get_consent(ctx: &Context) -> Arc<Consent> {
+    let data = ctx.data.read().await;
+    data.get::<ConsentKey>()
+        .cloned()
+        .expect("Consent not found")
+}
+
+pub async fn is_opted_out(ctx: &Context, user_id: u64) -> bool {
+    let data = ctx.data.read().await;
+    data.get::<ConsentKey>()
+        .is_some_and(|consent| consent.is_opted_out(user_id))
+}
+
+/// Adds to the user's consent history.
+pub async fn audit(ctx: &Context, user_id: u64, guild_id: Option<GuildId>, action: ConsentAction) {
+    info!("Consent: {} {:?}", user_id, action);
+
+    let consent = get_consent(ctx).await;
+    let _guard = consent.saving.lock().await;
+
+    let store = get_store(ctx).await;
+    let key = user_id.to_string();
+    let mut entries: Vec<AuditEntry> = store.get(AUDIT_TABLE, &key).unwrap_or_default();
+    entries.push(AuditEntry {
+        at: Utc::now(),
+        guild_id,
+        action,
+    });
+
+    if let Err(e) = store.put(AUDIT_TABLE, &key, &entries) {
+        error!("Failed to save consent audit log: {:?}", e);
+    }
+}
+
+/// Shows a recording status while any session is running.
+pub async fn update_status(ctx: &Context) {
+    let sessions = get_sessions(ctx).await.len();
+    let activity = match sessions {
+        0 => None,
+        1 => Some(ActivityData::custom("🔴 Recording voice chat")),
+        n => Some(ActivityData::custom(format!(
+            "🔴 Recording voice chat in {n} servers"
+        ))),
+    };
+    ctx.set_activity(activity);
+}
+
+async fn set_opted_out(ctx: &Context, msg: &Message, opted_out: bool) -> bool {
+    let consent = get_consent(ctx).await;
+    let store = get_store(ctx).await;
+    let user_id = msg.author.id.get();
+
+    if !consent.set_opted_out(&store, user_id, opted_out).await {
+        return false;
+    }
+
+    let action = if opted_out {
+        ConsentAction::OptedOut
+    } else {
+        ConsentAction::OptedIn
+    };
+    audit(ctx, user_id, msg.guild_id, action).await;
+
+    if opted_out {
+        // Throw away anything they were in the middle of saying
+        for session in get_sessions(ctx).await.iter() {
+            session.controller.forget_user(user_id);
+        }
+    }
+
+    true
+}
+
+#[command]
+pub async fn optout(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let reply = if set_opted_out(ctx, msg, true).await {
+        "Got it, your voice won't be recorded or transcribed anywhere from now on."
+    } else {
+        "You've already opted out of recording."
+    };
+    let _ = msg.channel_id.say(&ctx.http, reply).await;
+
+    Ok(())
+}
+
+#[command]
+pub async fn optin(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let reply = if set_opted_out(ctx, msg, false).await {
+        "Your voice will be recorded again while I'm in a channel with you."
+    } else {
+        "You haven't opted out of recording."
+    };
+    let _ = msg.channel_id.say(&ctx.http, reply).await;
+
+    Ok(())
+}
@@ -0,0 +1,349 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info};
+use rand::seq::SliceRandom;
+use serenity::all::GuildId;
+use serenity::async_trait;
+use serenity::client::Context;
+use serenity::framework::standard::macros::command;
+use serenity::framework::standard::{Args, CommandResult};
+use serenity::model::channel::Message;
+use songbird::tracks::PlayMode;
+use songbird::{Call, Event, EventContext as Ctx, EventHandler};
+use tokio::sync::Mutex;
+
+use crate::music::{enqueue, format_duration, get_track_info};
+use crate::state::LoopModeKey;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LoopMode {
+    #[default]
+    Off,
+    /// Repeat the current track
+    Track,
+    /// Put each finished track back at the end of the queue
+    Queue,
+}
+
+/// Gets the guild's call, but only if the author is in the same voice channel
+/// as the bot. Replies with the reason otherwise.
+pub async fn caller_call(ctx: &Context, msg: &Message) -> Option<Arc<Mutex<Call>>> {
+    let guild_id = msg.guild_id?;
+    let author_channel = msg.guild(&ctx.cache).and_then(|guild| {
+        guild
+            .voice_states
+            .get(&msg.author.id)
+            .and_then(|voice_state| voice_state.channel_id)
+    });
+
+    let manager = songbird::get(ctx).await.unwrap().clone();
+    let Some(call) = manager.get(guild_id) else {
+        let _ = msg
+            .channel_id
+            .say(&ctx.http, "I'm not in a voice channel.")
+            .await;
+        return None;
+    };
+
+    let bot_channel = call.lock().await.current_channel();
+    if author_channel.map(|c| c.get()) != bot_channel.map(|c| c.0.get()) {
+        let _ = msg
+            .channel_id
+            .say(&ctx.http, "You need to be in my voice channel to do that.")
+            .await;
+        return None;
+    }
+
+    Some(call)
+}
+
+async fn reply(ctx: &Context, msg: &Message, text: impl Into<String>) {
+    if let Err(e) = msg.channel_id.say(&ctx.http, text.into()).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+/// Parses `90`, `1:30` or `1:01:30` into a duration.
+fn parse_timestamp(text: &str) -> Option<Duration> {
+    let mut secs = 0;
+    for part in text.trim().split(':') {
+        secs = secs * 60 + part.parse::<u64>().ok()?;
+    }
+
+    Some(Duration::from_secs(secs))
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn pause(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let Some(call) = caller_call(ctx, msg).await else {
+        return Ok(());
+    };
+
+    let queue = call.lock().await.queue().clone();
+    match queue.pause() {
+        Ok(_) => reply(ctx, msg, "Paused.").await,
+        Err(_) => reply(ctx, msg, "Nothing is playing.").await,
+    }
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn resume(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let Some(call) = caller_call(ctx, msg).await else {
+        return Ok(());
+    };
+
+    let queue = call.lock().await.queue().clone();
+    match queue.resume() {
+        Ok(_) => reply(ctx, msg, "Resumed.").await,
+        Err(_) => reply(ctx, msg, "Nothing to resume.").await,
+    }
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn seek(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let Some(position) = parse_timestamp(args.message()) else {
+        reply(ctx, msg, "Usage: `~seek 1:30`").await;
+        return Ok(());
+    };
+
+    let Some(call) = caller_call(ctx, msg).await else {
+        return Ok(());
+    };
+
+    let Some(track) = call.lock().await.queue().current() else {
+        reply(ctx, msg, "Nothing is playing.").await;
+        return Ok(());
+    };
+
+    match track.seek_async(position).await {
+        Ok(position) => reply(ctx, msg, format!("Seeked to {}", format_duration(position))).await,
+        Err(e) => {
+            error!("Seek failed: {:?}", e);
+            reply(ctx, msg, "Couldn't seek in this track.").await;
+        }
+    }
+
+    Ok(())
+}
+
+#[command("loop")]
+#[only_in(guilds)]
+pub async fn repeat(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let mode = match args.message().trim() {
+        "track" | "song" | "" => LoopMode::Track,
+        "queue" | "all" => LoopMode::Queue,
+        "off" => LoopMode::Off,
+        _ => {
+            reply(ctx, msg, "Usage: `~loop track|queue|off`").await;
+            return Ok(());
+        }
+    };
+
+    let Some(call) = caller_call(ctx, msg).await else {
+        return Ok(());
+    };
+
+    let guild_id = msg.guild_id.unwrap();
+    let current = call.lock().await.queue().current();
+
+    if let Some(track) = current {
+        let _ = match mode {
+            LoopMode::Track => track.enable_loop(),
+            _ => track.disable_loop(),
+        };
+    }
+
+    {
+        let data = ctx.data.read().await;
+        if let Some(loop_modes) = data.get::<LoopModeKey>() {
+            loop_modes.insert(guild_id, mode);
+        }
+    }
+
+    let text = match mode {
+        LoopMode::Off => "Looping off.",
+        LoopMode::Track => "Looping the current track.",
+        LoopMode::Queue => "Looping the queue.",
+    };
+    reply(ctx, msg, text).await;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn shuffle(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let Some(call) = caller_call(ctx, msg).await else {
+        return Ok(());
+    };
+
+    let queue = call.lock().await.queue().clone();
+    queue.modify_queue(|tracks| {
+        // Leave the current track where it is
+        if tracks.len() > 2 {
+            tracks.make_contiguous()[1..].shuffle(&mut rand::thread_rng());
+        }
+    });
+
+    reply(
+        ctx,
+        msg,
+        format!("Shuffled {} tracks.", queue.len().saturating_sub(1)),
+    )
+    .await;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let Ok(index) = args.single::<usize>() else {
+        reply(ctx, msg, "Usage: `~remove <position>`").await;
+        return Ok(());
+    };
+
+    let Some(call) = caller_call(ctx, msg).await else {
+        return Ok(());
+    };
+
+    if index == 0 {
+        reply(ctx, msg, "Use `~skip` to remove the current track.").await;
+        return Ok(());
+    }
+
+    let queue = call.lock().await.queue().clone();
+    match queue.dequeue(index) {
+        Some(queued) => {
+            let title = get_track_info(&queued)
+                .await
+                .map(|info| info.title)
+                .unwrap_or_else(|| "track".to_string());
+            let _ = queued.stop();
+
+            reply(ctx, msg, format!("Removed **{title}** from the queue.")).await;
+        }
+        None => reply(ctx, msg, format!("There's no track at position {index}.")).await,
+    }
+
+    Ok(())
+}
+
+#[command("move")]
+#[only_in(guilds)]
+pub async fn move_track(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let (Ok(from), Ok(to)) = (args.single::<usize>(), args.single::<usize>()) else {
+        reply(ctx, msg, "Usage: `~move <from> <to>`").await;
+        return Ok(());
+    };
+
+    let Some(call) = caller_call(ctx, msg).await else {
+        return Ok(());
+    };
+
+    let queue = call.lock().await.queue().clone();
+    let moved = queue.modify_queue(|tracks| {
+        // Position 0 is the track that's playing, which can't be moved
+        if from == 0 || to == 0 || from >= tracks.len() || to >= tracks.len() {
+            return false;
+        }
+
+        if let Some(track) = tracks.remove(from) {
+            tracks.insert(to, track);
+        }
+        true
+    });
+
+    if moved {
+        reply(ctx, msg, format!("Moved track {from} to position {to}.")).await;
+    } else {
+        reply(ctx, msg, "Invalid queue positions.").await;
+    }
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn clear(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let Some(call) = caller_call(ctx, msg).await else {
+        return Ok(());
+    };
+
+    let queue = call.lock().await.queue().clone();
+    let removed = queue.modify_queue(|tracks| {
+        tracks
+            .drain(1..)
+            .map(|queued| {
+                let _ = queued.stop();
+            })
+            .count()
+    });
+
+    reply(ctx, msg, format!("Cleared {removed} upcoming tracks.")).await;
+
+    Ok(())
+}
+
+/// Global track end handler that puts finished queue tracks back at the end of
+/// the queue while the guild is looping the queue.
+pub struct QueueLooper {
+    pub ctx: Context,
+    pub guild_id: GuildId,
+}
+
+#[async_trait]
+impl EventHandler for QueueLooper {
+    async fn act(&self, ctx: &Ctx<'_>) -> Option<Event> {
+        let Ctx::Track(tracks) = ctx else {
+            return None;
+        };
+
+        let looping = {
+            let data = self.ctx.data.read().await;
+            data.get::<LoopModeKey>()
+                .and_then(|modes| modes.get(&self.guild_id).map(|mode| *mode))
+                == Some(LoopMode::Queue)
+        };
+        if !looping {
+            return None;
+        }
+
+        for (state, handle) in *tracks {
+            // Skipped and stopped tracks stay gone
+            if !matches!(state.playing, PlayMode::End) {
+                continue;
+            }
+
+            // Only music has track info; TTS replies aren't requeued
+            let Some(info) = get_track_info(handle).await else {
+                continue;
+            };
+
+            let manager = songbird::get(&self.ctx).await.unwrap().clone();
+            if let Some(call) = manager.get(self.guild_id) {
+                info!("Looping {}", info.title);
+
+                let input = info.input(&self.ctx).await;
+                enqueue(
+                    &self.ctx,
+                    self.guild_id,
+                    &mut *call.lock().await,
+                    input,
+                    info,
+                )
+                .await;
+            }
+        }
+
+        None
+    }
+}
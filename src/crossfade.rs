@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use serenity::all::GuildId;
+use serenity::async_trait;
+use serenity::client::Context;
+use songbird::tracks::{LoopState, TrackHandle};
+use songbird::{Event, EventContext as Ctx, EventHandler};
+
+use crate::music::set_track_fade;
+
+/// Start loading the next track this long before the crossfade, to give
+/// yt-dlp time to spin up.
+const PRELOAD_LEAD: Duration = Duration::from_secs(10);
+/// Volume updates per second while fading.
+const FADE_RATE: u32 = 20;
+/// How often a track's position is checked against the crossfade point.
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// When to start loading the track after this one so the transition is
+/// gapless, or `None` if the track's length isn't known.
+pub fn preload_time(duration: Option<Duration>, fade: Duration) -> Option<Duration> {
+    duration.map(|duration| duration.saturating_sub(fade + PRELOAD_LEAD))
+}
+
+/// Starts the next track fading in while `handle` fades out over its last
+/// `fade` of playback. The track's position is checked as it plays, rather
+/// than timing from when it started, so seeking moves the crossfade with it.
+pub fn schedule(
+    ctx: &Context,
+    guild_id: GuildId,
+    handle: &TrackHandle,
+    duration: Option<Duration>,
+    fade: Duration,
+) {
+    let Some(duration) = duration else {
+        return;
+    };
+    if fade.is_zero() || duration <= fade {
+        return;
+    }
+
+    let _ = handle.add_event(
+        Event::Periodic(CHECK_INTERVAL, None),
+        Crossfade {
+            ctx: ctx.clone(),
+            guild_id,
+            start: duration - fade,
+            fade,
+        },
+    );
+}
+
+struct Crossfade {
+    ctx: Context,
+    guild_id: GuildId,
+    /// Position in the track to start fading at
+    start: Duration,
+    fade: Duration,
+}
+
+#[async_trait]
+impl EventHandler for Crossfade {
+    async fn act(&self, ctx: &Ctx<'_>) -> Option<Event> {
+        let Ctx::Track(tracks) = ctx else {
+            return None;
+        };
+
+        for (state, outgoing) in *tracks {
+            if state.position < self.start {
+                continue;
+            }
+            // A looping track plays again rather than handing over
+            if !matches!(state.loops, LoopState::Finite(0)) {
+                continue;
+            }
+
+            let manager = songbird::get(&self.ctx).await.unwrap().clone();
+            let Some(call) = manager.get(self.guild_id) else {
+                continue;
+            };
+            let upcoming = call.lock().await.queue().current_queue();
+
+            // Only fade from the head of the queue into whatever is next
+            let (Some(current), Some(incoming)) = (upcoming.first(), upcoming.get(1)) else {
+                continue;
+            };
+            if current.uuid() != outgoing.uuid() {
+                continue;
+            }
+
+            let (outgoing, incoming) = ((*outgoing).clone(), incoming.clone());
+            let fade = self.fade;
+
+            tokio::spawn(async move {
+                set_track_fade(&incoming, 0.0).await;
+                let _ = incoming.play();
+
+                // The queue moves on by itself once the outgoing track ends,
+                // and just keeps playing the incoming one
+                let steps = (fade.as_millis() as u32 * FADE_RATE / 1000).max(1);
+                for step in 1..=steps {
+                    tokio::time::sleep(fade / steps).await;
+
+                    let progress = step as f32 / steps as f32;
+                    set_track_fade(&outgoing, 1.0 - progress).await;
+                    set_track_fade(&incoming, progress).await;
+                }
+            });
+
+            return Some(Event::Cancel);
+        }
+
+        None
+    }
+}
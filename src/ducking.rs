@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+
+/// Length of a VoiceTick, which is how often the ducker is stepped.
+const TICK_MS: f32 = 20.0;
+
+/// Smoothly lowers the music while someone is talking (or the bot is
+/// replying), then brings it back up once they're done.
+pub struct Ducker {
+    gain: Mutex<f32>,
+}
+
+impl Ducker {
+    pub fn new() -> Self {
+        Self {
+            gain: Mutex::new(1.0),
+        }
+    }
+
+    /// Whether the music is at full volume.
+    pub fn is_released(&self) -> bool {
+        self.gain.lock().map(|gain| *gain >= 1.0).unwrap_or(true)
+    }
+
+    /// Advances the gain one tick towards `target`, moving the full duck depth
+    /// over `attack_ms` going down and `release_ms` coming back up.
+    ///
+    /// Returns the new gain, or `None` if the music is at full volume and
+    /// doesn't need touching.
+    pub fn step(&self, target: f32, depth: f32, attack_ms: u64, release_ms: u64) -> Option<f32> {
+        let mut gain = self.gain.lock().ok()?;
+
+        if *gain >= 1.0 && target >= 1.0 {
+            return None;
+        }
+
+        // Never ramp slower than a 10% duck would, so turning ducking off
+        // mid-duck still brings the music back up
+        let ramp = |ms: u64| depth.max(0.1) * TICK_MS / (ms as f32).max(TICK_MS);
+
+        *gain = if target < *gain {
+            (*gain - ramp(attack_ms)).max(target)
+        } else {
+            (*gain + ramp(release_ms)).min(target)
+        };
+
+        Some(*gain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_full_volume_alone() {
+        let ducker = Ducker::new();
+        assert_eq!(ducker.step(1.0, 0.6, 100, 800), None);
+        assert!(ducker.is_released());
+    }
+
+    #[test]
+    fn ducks_over_the_attack_and_releases_over_the_release() {
+        let ducker = Ducker::new();
+
+        // 60% over 100 ms is 12% per 20 ms tick
+        let gain = ducker.step(0.4, 0.6, 100, 800).unwrap();
+        assert!((gain - 0.88).abs() < 1e-6);
+
+        for _ in 0..10 {
+            ducker.step(0.4, 0.6, 100, 800);
+        }
+        assert_eq!(ducker.step(0.4, 0.6, 100, 800), Some(0.4));
+        assert!(!ducker.is_released());
+
+        // 60% over 800 ms is 1.5% per tick
+        let gain = ducker.step(1.0, 0.6, 100, 800).unwrap();
+        assert!((gain - 0.415).abs() < 1e-6);
+
+        for _ in 0..100 {
+            ducker.step(1.0, 0.6, 100, 800);
+        }
+        assert!(ducker.is_released());
+    }
+
+    #[test]
+    fn still_releases_after_ducking_is_turned_off() {
+        let ducker = Ducker::new();
+        ducker.step(0.4, 0.6, 0, 0);
+
+        // Falls back to a 10% ramp, 0.25% per tick over 800 ms, so 0.6 takes
+        // about 240 ticks
+        for _ in 0..250 {
+            ducker.step(1.0, 0.0, 100, 800);
+        }
+        assert!(ducker.is_released());
+    }
+}
@@ -0,0 +1,524 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+use crypto_secretbox::aead::{Aead, KeyInit};
+use crypto_secretbox::{Key, Nonce, XSalsa20Poly1305};
+use log::{error, info};
+use rand::RngCore;
+use serenity::client::Context;
+
+use crate::state::RecordingKey;
+
+/// Identifies an encrypted file, and the version of the format.
+const MAGIC: &[u8; 8] = b"ADAMENC1";
+const FILE_ID_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + FILE_ID_LEN;
+/// Plaintext is sealed this much at a time, so files can be written (and
+/// appended to) as a stream.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Poly1305 tag added to every chunk.
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Encrypted files get this added to their name.
+pub const EXTENSION: &str = "enc";
+
+fn parse_key(hex: &str) -> Result<Key, Error> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(Error::msg("key must be 64 hex characters (32 bytes)"));
+    }
+
+    let mut key = Key::default();
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair)?;
+        *byte = u8::from_str_radix(pair, 16)?;
+    }
+    Ok(key)
+}
+
+/// The key recordings are encrypted with, from `RECORDING_KEY` or the file
+/// named by `RECORDING_KEY_FILE`. `None` means recordings are stored as is.
+/// Read once at startup; the bot gets it from `get_recording_key`.
+pub fn recording_key() -> Result<Option<Key>, Error> {
+    if let Ok(hex) = env::var("RECORDING_KEY") {
+        if !hex.trim().is_empty() {
+            return parse_key(&hex).map(Some);
+        }
+    }
+
+    if let Ok(path) = env::var("RECORDING_KEY_FILE") {
+        if !path.trim().is_empty() {
+            let hex = fs::read_to_string(path.trim())?;
+            return parse_key(&hex).map(Some);
+        }
+    }
+
+    Ok(None)
+}
+
+/// A new random key, hex encoded, for `adam keygen`.
+pub fn generate_key() -> String {
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    key.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Each chunk's nonce is the file's random id, the chunk's index and whether
+/// it's the end marker, so chunks can't be reordered, swapped between files or
+/// cut off the end without decryption failing. No index is ever sealed twice:
+/// appends only add chunks after the existing ones.
+fn nonce(file_id: &[u8; FILE_ID_LEN], index: u64, last: bool) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..FILE_ID_LEN].copy_from_slice(file_id);
+    nonce[FILE_ID_LEN..NONCE_LEN - 1]
+        .copy_from_slice(&index.to_le_bytes()[..NONCE_LEN - 1 - FILE_ID_LEN]);
+    nonce[NONCE_LEN - 1] = last as u8;
+    nonce
+}
+
+/// Writes a file as a header followed by chunks of `length | ciphertext`,
+/// ending with an empty chunk marked as the last. A file is only valid once
+/// `finish` has sealed that end marker.
+pub struct EncryptedWriter<W: Write> {
+    inner: W,
+    cipher: XSalsa20Poly1305,
+    file_id: [u8; FILE_ID_LEN],
+    index: u64,
+    buf: Vec<u8>,
+}
+
+impl EncryptedWriter<File> {
+    pub fn create(path: &Path, key: &Key) -> io::Result<Self> {
+        let mut file_id = [0u8; FILE_ID_LEN];
+        rand::thread_rng().fill_bytes(&mut file_id);
+
+        let mut file = File::create(path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&file_id)?;
+
+        Ok(Self::new(file, key, file_id, 0))
+    }
+
+    /// Carries on writing after what's already in the file, or starts a new
+    /// one. Only the end marker is taken off; the chunks before it are left
+    /// exactly as they were, and new data goes in chunks after them.
+    pub fn append(path: &Path, key: &Key) -> Result<Self, Error> {
+        if !path.exists() {
+            return Ok(Self::create(path, key)?);
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let file_id = read_header(&mut file)?;
+
+        let mut last = None;
+        let mut offset = HEADER_LEN as u64;
+        let mut index = 0;
+        let mut len = [0u8; 4];
+        while read_len(&mut file, &mut len)? {
+            last = Some((offset, index));
+            offset = file.seek(SeekFrom::Current(u32::from_le_bytes(len) as i64))?;
+            index += 1;
+        }
+        let Some((offset, index)) = last else {
+            return Err(Error::msg("encrypted file has no chunks"));
+        };
+
+        // Make sure what's being taken off really is the end marker
+        file.seek(SeekFrom::Start(offset))?;
+        let sealed = read_chunk(&mut file, index)?
+            .ok_or_else(|| Error::msg("encrypted file has no chunks"))?;
+        let mut writer = Self::new(file, key, file_id, index);
+        open_chunk(&writer.cipher, &file_id, index, true, &sealed)?;
+
+        writer.inner.set_len(offset)?;
+        writer.inner.seek(SeekFrom::Start(offset))?;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> EncryptedWriter<W> {
+    fn new(inner: W, key: &Key, file_id: [u8; FILE_ID_LEN], index: u64) -> Self {
+        Self {
+            inner,
+            cipher: XSalsa20Poly1305::new(key),
+            file_id,
+            index,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let sealed = self
+            .cipher
+            .encrypt(&nonce(&self.file_id, self.index, last), self.buf.as_slice())
+            .map_err(|_| io::Error::other("encryption failed"))?;
+
+        self.inner.write_all(&(sealed.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&sealed)?;
+        self.index += 1;
+        self.buf.clear();
+
+        Ok(())
+    }
+
+    /// Seals whatever is left over, then the end marker.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.buf.is_empty() {
+            self.seal(false)?;
+        }
+        self.seal(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() == CHUNK_SIZE {
+            self.seal(false)?;
+        }
+
+        let n = data.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn read_header(reader: &mut impl Read) -> io::Result<[u8; FILE_ID_LEN]> {
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an encrypted recording",
+        ));
+    }
+
+    let mut file_id = [0u8; FILE_ID_LEN];
+    file_id.copy_from_slice(&header[MAGIC.len()..]);
+    Ok(file_id)
+}
+
+/// Reads a chunk's length, returning false at the end of the file.
+fn read_len(reader: &mut impl Read, len: &mut [u8; 4]) -> io::Result<bool> {
+    match reader.read_exact(len) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the next sealed chunk, or returns `None` at the end of the file.
+fn read_chunk(reader: &mut impl Read, index: u64) -> Result<Option<Vec<u8>>, Error> {
+    let mut len = [0u8; 4];
+    if !read_len(reader, &mut len)? {
+        return Ok(None);
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+    if !(TAG_LEN..=CHUNK_SIZE + TAG_LEN).contains(&len) {
+        return Err(Error::msg(format!("chunk {index} has a bad length")));
+    }
+    let mut sealed = vec![0u8; len];
+    reader.read_exact(&mut sealed)?;
+    Ok(Some(sealed))
+}
+
+fn open_chunk(
+    cipher: &XSalsa20Poly1305,
+    file_id: &[u8; FILE_ID_LEN],
+    index: u64,
+    last: bool,
+    sealed: &[u8],
+) -> Result<Vec<u8>, Error> {
+    cipher
+        .decrypt(&nonce(file_id, index, last), sealed)
+        .map_err(|_| Error::msg(format!("chunk {index} failed to authenticate")))
+}
+
+/// Decrypts a whole file written by `EncryptedWriter`, failing if anything
+/// was changed or cut off.
+pub fn decrypt(path: &Path, key: &Key) -> Result<Vec<u8>, Error> {
+    let size = fs::metadata(path)?.len();
+    let mut reader = BufReader::new(File::open(path)?);
+    let file_id = read_header(&mut reader)?;
+    let cipher = XSalsa20Poly1305::new(key);
+
+    let mut plaintext = Vec::new();
+    let mut offset = HEADER_LEN as u64;
+    let mut index = 0;
+    while let Some(sealed) = read_chunk(&mut reader, index)? {
+        offset += 4 + sealed.len() as u64;
+        // Only the chunk that runs to the end of the file may be the end marker
+        let last = offset == size;
+        let chunk = open_chunk(&cipher, &file_id, index, last, &sealed)?;
+
+        if last {
+            if !chunk.is_empty() {
+                return Err(Error::msg("end marker isn't empty"));
+            }
+            return Ok(plaintext);
+        }
+        plaintext.extend_from_slice(&chunk);
+        index += 1;
+    }
+
+    Err(Error::msg("file is truncated: its last chunk is missing"))
+}
+
+fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{EXTENSION}"));
+    PathBuf::from(name)
+}
+
+/// The key parsed at startup, if recordings are encrypted.
+pub async fn get_recording_key(ctx: &Context) -> Option<Key> {
+    let data = ctx.data.read().await;
+    data.get::<RecordingKey>().cloned().flatten()
+}
+
+/// Writes a recording or transcript, encrypted if there's a key. Returns the
+/// path it actually ended up at.
+pub fn write_file(path: &Path, contents: &[u8], key: Option<&Key>) -> Result<PathBuf, Error> {
+    let Some(key) = key else {
+        fs::write(path, contents)?;
+        return Ok(path.to_path_buf());
+    };
+
+    let path = encrypted_path(path);
+    let mut writer = EncryptedWriter::create(&path, key)?;
+    writer.write_all(contents)?;
+    writer.finish()?;
+    Ok(path)
+}
+
+/// Adds to the end of a file, encrypted if there's a key.
+pub fn append_file(path: &Path, contents: &[u8], key: Option<&Key>) -> Result<(), Error> {
+    let Some(key) = key else {
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?
+            .write_all(contents)?;
+        return Ok(());
+    };
+    if contents.is_empty() {
+        return Ok(());
+    }
+
+    let mut writer = EncryptedWriter::append(&encrypted_path(path), key)?;
+    writer.write_all(contents)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Reads back a file saved by `write_file`.
+pub fn read_file(path: &Path, key: Option<&Key>) -> Result<Vec<u8>, Error> {
+    if path.extension().is_some_and(|ext| ext == EXTENSION) {
+        let key = key.ok_or_else(|| Error::msg("no recording key set"))?;
+        return decrypt(path, key);
+    }
+
+    Ok(fs::read(path)?)
+}
+
+/// `adam decrypt <file or dir> [output]`: decrypts a recording, or every
+/// encrypted file in a session directory, for export.
+pub fn decrypt_command(args: &[String]) -> Result<(), Error> {
+    let input = PathBuf::from(
+        args.first()
+            .ok_or_else(|| Error::msg("usage: adam decrypt <file or dir> [output]"))?,
+    );
+    let key = recording_key()?
+        .ok_or_else(|| Error::msg("set RECORDING_KEY or RECORDING_KEY_FILE to decrypt"))?;
+
+    let strip = |path: &Path| path.with_extension("");
+
+    if input.is_file() {
+        let output = args
+            .get(1)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| strip(&input));
+        fs::write(&output, decrypt(&input, &key)?)?;
+        info!("Decrypted {} to {}", input.display(), output.display());
+        return Ok(());
+    }
+
+    let output = args.get(1).map(PathBuf::from).unwrap_or_else(|| {
+        let mut name = input.as_os_str().to_owned();
+        name.push("-decrypted");
+        PathBuf::from(name)
+    });
+    fs::create_dir_all(&output)?;
+
+    let mut failed = 0;
+    for entry in fs::read_dir(&input)?.flatten() {
+        let path = entry.path();
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+
+        if path.extension().is_some_and(|ext| ext == EXTENSION) {
+            match decrypt(&path, &key) {
+                Ok(plaintext) => fs::write(output.join(strip(Path::new(name))), plaintext)?,
+                Err(e) => {
+                    error!("Couldn't decrypt {}: {:?}", path.display(), e);
+                    failed += 1;
+                }
+            }
+        } else if path.is_file() {
+            fs::copy(&path, output.join(name))?;
+        }
+    }
+
+    info!("Decrypted {} to {}", input.display(), output.display());
+    if failed > 0 {
+        return Err(Error::msg(format!("{failed} files failed to decrypt")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let mut id = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut id);
+        let id: String = id.iter().map(|byte| format!("{byte:02x}")).collect();
+        env::temp_dir().join(format!("adam-{id}-{name}"))
+    }
+
+    fn key() -> Key {
+        parse_key(&generate_key()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_across_chunks() {
+        let key = key();
+        let path = temp_path("slice.wav");
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 2 + 123).map(|i| i as u8).collect();
+
+        let written = write_file(&path, &contents, Some(&key)).unwrap();
+        assert_eq!(written.extension().unwrap(), EXTENSION);
+        assert_eq!(read_file(&written, Some(&key)).unwrap(), contents);
+
+        fs::remove_file(written).unwrap();
+    }
+
+    #[test]
+    fn round_trips_empty_and_exactly_one_chunk() {
+        let key = key();
+        for len in [0, CHUNK_SIZE] {
+            let path = temp_path("exact.txt");
+            let contents = vec![7u8; len];
+
+            let written = write_file(&path, &contents, Some(&key)).unwrap();
+            assert_eq!(decrypt(&written, &key).unwrap(), contents);
+
+            fs::remove_file(written).unwrap();
+        }
+    }
+
+    #[test]
+    fn appends_keep_the_file_valid() {
+        let key = key();
+        let path = temp_path("ssrc_userid_map.txt");
+
+        append_file(&path, b"1:100\n", Some(&key)).unwrap();
+        append_file(&path, b"2:200\n", Some(&key)).unwrap();
+
+        let encrypted = encrypted_path(&path);
+        assert_eq!(decrypt(&encrypted, &key).unwrap(), b"1:100\n2:200\n");
+
+        fs::remove_file(encrypted).unwrap();
+    }
+
+    #[test]
+    fn appends_never_reseal_a_chunk() {
+        let key = key();
+        let path = temp_path("transcript.json");
+        let encrypted = encrypted_path(&path);
+
+        append_file(&path, b"first", Some(&key)).unwrap();
+        let before = fs::read(&encrypted).unwrap();
+        append_file(&path, b"second", Some(&key)).unwrap();
+        let after = fs::read(&encrypted).unwrap();
+
+        // Only the end marker goes; the sealed data is left byte for byte
+        let marker = 4 + TAG_LEN;
+        assert_eq!(
+            &after[..before.len() - marker],
+            &before[..before.len() - marker]
+        );
+        assert_eq!(decrypt(&encrypted, &key).unwrap(), b"firstsecond");
+
+        // Every chunk sits at its own index, and only the final one is last
+        let mut reader = &after[..];
+        let file_id = read_header(&mut reader).unwrap();
+        let cipher = XSalsa20Poly1305::new(&key);
+        let mut chunks = Vec::new();
+        while let Some(sealed) = read_chunk(&mut reader, chunks.len() as u64).unwrap() {
+            chunks.push(sealed);
+        }
+        assert_eq!(chunks.len(), 3);
+        for (index, sealed) in chunks.iter().enumerate() {
+            let last = index == chunks.len() - 1;
+            assert!(open_chunk(&cipher, &file_id, index as u64, last, sealed).is_ok());
+        }
+
+        fs::remove_file(encrypted).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let key = key();
+        let path = temp_path("truncated.txt");
+        let contents = vec![1u8; CHUNK_SIZE + 10];
+        let written = write_file(&path, &contents, Some(&key)).unwrap();
+
+        // Drop the last chunk entirely
+        let first_chunk_end = HEADER_LEN + 4 + CHUNK_SIZE + TAG_LEN;
+        let bytes = fs::read(&written).unwrap();
+        fs::write(&written, &bytes[..first_chunk_end]).unwrap();
+        assert!(decrypt(&written, &key).is_err());
+
+        // Nothing but the header
+        fs::write(&written, &bytes[..HEADER_LEN]).unwrap();
+        assert!(decrypt(&written, &key).is_err());
+
+        fs::remove_file(written).unwrap();
+    }
+
+    #[test]
+    fn rejects_the_wrong_key_and_tampering() {
+        let key = key();
+        let path = temp_path("tampered.txt");
+        let written = write_file(&path, b"hello", Some(&key)).unwrap();
+
+        assert!(decrypt(&written, &self::key()).is_err());
+
+        let mut bytes = fs::read(&written).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&written, &bytes).unwrap();
+        assert!(decrypt(&written, &key).is_err());
+
+        fs::remove_file(written).unwrap();
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert!(parse_key(&"ab".repeat(32)).is_ok());
+        assert!(parse_key("abcd").is_err());
+        assert!(parse_key(&"zz".repeat(32)).is_err());
+    }
+}
@@ -1,8 +1,13 @@
 use log::{error, info};
+use serde::{Deserialize, Serialize};
 
 use crate::bot::Bot;
 
-#[derive(Debug, Clone)]
+/// Where the chat history is kept across restarts.
+pub const HISTORY_TABLE: &str = "history";
+pub const HISTORY_KEY: &str = "messages";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SavedMessage {
     pub author: String,
     pub content: String,
@@ -0,0 +1,193 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+use serenity::all::{ChannelId, GuildId};
+use serenity::client::Context;
+
+use crate::settings::guild_settings;
+use crate::state::IdleKey;
+use crate::voice::leave_guild;
+
+/// What the bot has been doing in a guild's voice channel, for deciding when
+/// to leave on its own.
+pub struct IdleTracker {
+    /// Where to say why the bot left
+    notice_channel: ChannelId,
+    /// When the last person left the bot's channel
+    alone_since: Option<Instant>,
+    /// When the last speech or playback stopped
+    quiet_since: Option<Instant>,
+}
+
+#[derive(Clone, Copy)]
+enum IdleReason {
+    Alone,
+    Quiet,
+}
+
+/// Starts watching a guild the bot just joined.
+pub async fn watch(ctx: &Context, guild_id: GuildId, notice_channel: ChannelId) {
+    let data = ctx.data.read().await;
+    if let Some(trackers) = data.get::<IdleKey>() {
+        trackers.insert(
+            guild_id,
+            IdleTracker {
+                notice_channel,
+                alone_since: None,
+                quiet_since: Some(Instant::now()),
+            },
+        );
+    }
+    drop(data);
+
+    schedule_check(ctx, guild_id).await;
+}
+
+pub async fn unwatch(ctx: &Context, guild_id: GuildId) {
+    let data = ctx.data.read().await;
+    if let Some(trackers) = data.get::<IdleKey>() {
+        trackers.remove(&guild_id);
+    }
+}
+
+/// Where the bot was asked to join from, for telling people why it left.
+pub async fn notice_channel(ctx: &Context, guild_id: GuildId) -> Option<ChannelId> {
+    let data = ctx.data.read().await;
+    data.get::<IdleKey>()
+        .and_then(|trackers| trackers.get(&guild_id).map(|t| t.notice_channel))
+}
+
+/// Called every voice tick with whether anyone is talking or anything is
+/// playing.
+pub async fn set_active(ctx: &Context, guild_id: GuildId, active: bool) {
+    let started_idling = {
+        let data = ctx.data.read().await;
+        let Some(mut tracker) = data.get::<IdleKey>().and_then(|t| t.get_mut(&guild_id)) else {
+            return;
+        };
+
+        match (active, tracker.quiet_since) {
+            (true, _) => {
+                tracker.quiet_since = None;
+                false
+            }
+            (false, None) => {
+                tracker.quiet_since = Some(Instant::now());
+                true
+            }
+            (false, Some(_)) => false,
+        }
+    };
+
+    if started_idling {
+        schedule_check(ctx, guild_id).await;
+    }
+}
+
+/// Called on voice state updates to notice when everyone has left the bot's
+/// channel, or someone has come back.
+pub async fn update_listeners(ctx: &Context, guild_id: GuildId) {
+    let manager = songbird::get(ctx).await.unwrap().clone();
+    let Some(call) = manager.get(guild_id) else {
+        return;
+    };
+    let Some(channel) = call.lock().await.current_channel() else {
+        return;
+    };
+
+    let bot_id = ctx.cache.current_user().id;
+    let listeners = ctx
+        .cache
+        .guild(guild_id)
+        .map(|guild| {
+            guild
+                .voice_states
+                .values()
+                .filter(|state| state.channel_id.map(|c| c.get()) == Some(channel.0.get()))
+                .filter(|state| state.user_id != bot_id)
+                .filter(|state| !state.member.as_ref().is_some_and(|m| m.user.bot))
+                .count()
+        })
+        .unwrap_or_default();
+
+    let started_idling = {
+        let data = ctx.data.read().await;
+        let Some(mut tracker) = data.get::<IdleKey>().and_then(|t| t.get_mut(&guild_id)) else {
+            return;
+        };
+
+        match (listeners, tracker.alone_since) {
+            (0, None) => {
+                info!("Everyone left the voice channel");
+                tracker.alone_since = Some(Instant::now());
+                true
+            }
+            (0, Some(_)) => false,
+            _ => {
+                tracker.alone_since = None;
+                false
+            }
+        }
+    };
+
+    if started_idling {
+        schedule_check(ctx, guild_id).await;
+    }
+}
+
+/// Checks again once the idle timeout has passed.
+async fn schedule_check(ctx: &Context, guild_id: GuildId) {
+    let timeout = guild_settings(ctx, guild_id).await.idle_timeout_secs;
+    if timeout == 0 {
+        return;
+    }
+
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(timeout)).await;
+        check(&ctx, guild_id).await;
+    });
+}
+
+/// Leaves if the guild has been idle for the whole timeout.
+async fn check(ctx: &Context, guild_id: GuildId) {
+    let timeout = guild_settings(ctx, guild_id).await.idle_timeout_secs;
+    if timeout == 0 {
+        return;
+    }
+    let timeout = Duration::from_secs(timeout);
+
+    let idle = {
+        let data = ctx.data.read().await;
+        let Some(tracker) = data.get::<IdleKey>().and_then(|t| t.get(&guild_id)) else {
+            return;
+        };
+
+        let expired = |since: Option<Instant>| since.is_some_and(|t| t.elapsed() >= timeout);
+        let reason = if expired(tracker.alone_since) {
+            Some(IdleReason::Alone)
+        } else if expired(tracker.quiet_since) {
+            Some(IdleReason::Quiet)
+        } else {
+            None
+        };
+
+        reason.map(|reason| (reason, tracker.notice_channel))
+    };
+
+    let Some((reason, notice_channel)) = idle else {
+        return;
+    };
+
+    let minutes = timeout.as_secs().div_ceil(60);
+    let notice = match reason {
+        IdleReason::Alone => "Everyone left, so I'm heading out too.".to_string(),
+        IdleReason::Quiet => {
+            format!("Nothing has happened for {minutes} minutes, so I'm leaving.")
+        }
+    };
+    info!("Leaving idle voice channel: {}", notice);
+
+    let _ = notice_channel.say(&ctx.http, notice).await;
+    leave_guild(ctx, guild_id, Some(notice_channel)).await;
+}
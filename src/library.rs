@@ -0,0 +1,367 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::Error;
+use log::{error, info, warn};
+use serenity::all::UserId;
+use serenity::builder::{CreateEmbed, CreateEmbedFooter, CreateMessage};
+use serenity::client::Context;
+use serenity::framework::standard::macros::command;
+use serenity::framework::standard::{Args, CommandResult};
+use serenity::model::channel::Message;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::Hint;
+
+use crate::controls::caller_call;
+use crate::music::{enqueue, format_duration, TrackInfo};
+use crate::state::LibraryKey;
+
+const EXTENSIONS: [&str; 7] = ["mp3", "m4a", "mp4", "aac", "flac", "wav", "ogg"];
+
+/// Most matches shown by `~library <query>`.
+const LIBRARY_RESULTS: usize = 10;
+
+#[derive(Clone, Debug)]
+pub struct LibraryTrack {
+    pub path: PathBuf,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+impl LibraryTrack {
+    fn read(path: &Path) -> Result<Self, Error> {
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+            hint.with_extension(ext);
+        }
+
+        let source = MediaSourceStream::new(Box::new(fs::File::open(path)?), Default::default());
+        let mut probed = symphonia::default::get_probe().format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?;
+
+        let mut track = Self {
+            path: path.to_path_buf(),
+            title: path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            artist: None,
+            album: None,
+            duration: probed.format.default_track().and_then(|track| {
+                let params = &track.codec_params;
+                let time = params.time_base?.calc_time(params.n_frames?);
+                Some(Duration::from_secs_f64(time.seconds as f64 + time.frac))
+            }),
+        };
+
+        // Tags can come before the container (ID3) or from the container itself
+        if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            track.apply_tags(revision);
+        }
+        if let Some(revision) = probed.format.metadata().current() {
+            track.apply_tags(revision);
+        }
+
+        Ok(track)
+    }
+
+    fn apply_tags(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            let value = tag.value.to_string();
+            if value.trim().is_empty() {
+                continue;
+            }
+
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => self.title = value,
+                Some(StandardTagKey::Artist) => self.artist = Some(value),
+                Some(StandardTagKey::Album) => self.album = Some(value),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn info(&self, requester: Option<UserId>) -> TrackInfo {
+        TrackInfo {
+            title: self.title.clone(),
+            url: self.path.to_string_lossy().to_string(),
+            artist: self.artist.clone(),
+            duration: self.duration,
+            thumbnail: None,
+            requester,
+            stream_title: None,
+        }
+    }
+}
+
+/// An index of the audio files under `MUSIC_DIR`, for playing music without
+/// yt-dlp or a network connection.
+pub struct Library {
+    dir: PathBuf,
+    tracks: RwLock<Vec<LibraryTrack>>,
+}
+
+impl Library {
+    pub fn from_env() -> Option<Arc<Self>> {
+        let dir = env::var("MUSIC_DIR").ok().filter(|dir| !dir.is_empty())?;
+
+        Some(Arc::new(Self {
+            dir: PathBuf::from(dir),
+            tracks: RwLock::new(Vec::new()),
+        }))
+    }
+
+    /// Reads the tags of every audio file in the library. This is blocking
+    /// file IO, so run it off the async runtime.
+    pub fn scan(&self) -> usize {
+        let mut paths = Vec::new();
+        collect_files(&self.dir, &mut paths);
+
+        let mut tracks: Vec<LibraryTrack> = paths
+            .iter()
+            .filter_map(|path| {
+                LibraryTrack::read(path)
+                    .map_err(|e| warn!("Couldn't read {}: {:?}", path.display(), e))
+                    .ok()
+            })
+            .collect();
+        tracks.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let count = tracks.len();
+        info!("Indexed {} tracks in {}", count, self.dir.display());
+
+        if let Ok(mut index) = self.tracks.write() {
+            *index = tracks;
+        }
+
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.read().map(|tracks| tracks.len()).unwrap_or(0)
+    }
+
+    /// Finds tracks whose title, artist, album or file name contain the most
+    /// words of the query.
+    pub fn search(&self, query: &str, max_results: usize) -> Vec<LibraryTrack> {
+        let words: Vec<String> = query
+            .split_whitespace()
+            .map(|word| word.to_lowercase())
+            .collect();
+
+        let Ok(tracks) = self.tracks.read() else {
+            return Vec::new();
+        };
+
+        let mut matches: Vec<(usize, &LibraryTrack)> = tracks
+            .iter()
+            .map(|track| {
+                let haystack = format!(
+                    "{} {} {} {}",
+                    track.title,
+                    track.artist.as_deref().unwrap_or_default(),
+                    track.album.as_deref().unwrap_or_default(),
+                    track.path.file_name().unwrap_or_default().to_string_lossy()
+                )
+                .to_lowercase();
+                let score = words.iter().filter(|word| haystack.contains(*word)).count();
+                (score, track)
+            })
+            .filter(|(score, _)| *score > 0)
+            .collect();
+        matches.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+
+        matches
+            .into_iter()
+            .take(max_results)
+            .map(|(_, track)| track.clone())
+            .collect()
+    }
+}
+
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Couldn't read music directory {}: {:?}", dir.display(), e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Doesn't follow symlinks, so a link back up the tree can't loop
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            collect_files(&path, paths);
+        } else if path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        {
+            paths.push(path);
+        }
+    }
+}
+
+pub async fn get_library(ctx: &Context) -> Option<Arc<Library>> {
+    let data = ctx.data.read().await;
+    data.get::<LibraryKey>().cloned()
+}
+
+/// `title - artist (album) 3:45`
+fn line(track: &LibraryTrack) -> String {
+    let mut line = format!("**{}**", track.title);
+    if let Some(artist) = &track.artist {
+        line.push_str(&format!(" - {artist}"));
+    }
+    if let Some(album) = &track.album {
+        line.push_str(&format!(" ({album})"));
+    }
+    if let Some(duration) = track.duration {
+        line.push_str(&format!(" `{}`", format_duration(duration)));
+    }
+    line
+}
+
+#[command]
+#[only_in(guilds)]
+#[sub_commands(library_rescan)]
+pub async fn library(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let Some(library) = get_library(ctx).await else {
+        let _ = msg
+            .channel_id
+            .say(&ctx.http, "No local library configured (`MUSIC_DIR`).")
+            .await;
+        return Ok(());
+    };
+
+    let query = args.message().trim();
+
+    if query.is_empty() {
+        let _ = msg
+            .channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "{} tracks in the library. Search with `~library <query>`, \
+                     queue with `~local <query>`, reindex with `~library rescan`",
+                    library.len()
+                ),
+            )
+            .await;
+        return Ok(());
+    }
+
+    let results = library.search(query, LIBRARY_RESULTS);
+    if results.is_empty() {
+        let _ = msg
+            .channel_id
+            .say(
+                &ctx.http,
+                format!("Nothing in the library matches `{query}`"),
+            )
+            .await;
+        return Ok(());
+    }
+
+    let lines: Vec<String> = results
+        .iter()
+        .enumerate()
+        .map(|(i, track)| format!("**{}.** {}", i + 1, line(track)))
+        .collect();
+    let embed = CreateEmbed::new()
+        .title("Library")
+        .description(lines.join("\n"))
+        .footer(CreateEmbedFooter::new("Queue one with ~local <query>"));
+
+    let _ = msg
+        .channel_id
+        .send_message(&ctx.http, CreateMessage::new().embed(embed))
+        .await;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn local(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let Some(library) = get_library(ctx).await else {
+        let _ = msg
+            .channel_id
+            .say(&ctx.http, "No local library configured (`MUSIC_DIR`).")
+            .await;
+        return Ok(());
+    };
+
+    let query = args.message().trim();
+    let Some(track) = library.search(query, 1).pop() else {
+        let _ = msg
+            .channel_id
+            .say(
+                &ctx.http,
+                format!("Nothing in the library matches `{query}`"),
+            )
+            .await;
+        return Ok(());
+    };
+
+    let Some(call) = caller_call(ctx, msg).await else {
+        return Ok(());
+    };
+
+    info!("Queueing {}", track.path.display());
+
+    let info = track.info(Some(msg.author.id));
+    let input = info.input(ctx).await;
+
+    let mut handler = call.lock().await;
+    enqueue(ctx, msg.guild_id.unwrap(), &mut handler, input, info).await;
+
+    let _ = msg
+        .channel_id
+        .say(
+            &ctx.http,
+            format!(
+                "Added {} to queue: position {}",
+                line(&track),
+                handler.queue().len()
+            ),
+        )
+        .await;
+
+    Ok(())
+}
+
+/// Reindexes the whole of `MUSIC_DIR`, which is slow, so it's limited to
+/// people who can manage the server.
+#[command("rescan")]
+#[only_in(guilds)]
+#[required_permissions(MANAGE_GUILD)]
+pub async fn library_rescan(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let Some(library) = get_library(ctx).await else {
+        let _ = msg
+            .channel_id
+            .say(&ctx.http, "No local library configured (`MUSIC_DIR`).")
+            .await;
+        return Ok(());
+    };
+
+    let count = tokio::task::spawn_blocking(move || library.scan()).await?;
+    let _ = msg
+        .channel_id
+        .say(&ctx.http, format!("Indexed {count} tracks."))
+        .await;
+
+    Ok(())
+}
@@ -0,0 +1,397 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serenity::all::GuildId;
+use serenity::async_trait;
+use serenity::client::Context;
+use songbird::input::codecs::{CODEC_REGISTRY, PROBE};
+use songbird::input::core::audio::SampleBuffer;
+use songbird::input::{Input, LiveInput};
+use songbird::tracks::TrackHandle;
+use songbird::{Event, EventContext, EventHandler};
+use tokio::sync::mpsc;
+
+use crate::music::{set_track_gain, TrackInfo};
+use crate::settings::guild_settings;
+use crate::store::get_store;
+
+const TABLE: &str = "loudness";
+
+/// Never turn a track up or down by more than this, so a near-silent intro
+/// doesn't get blasted.
+const MAX_BOOST_DB: f64 = 12.0;
+const MAX_CUT_DB: f64 = 20.0;
+
+/// How much audio to measure between gain updates while a track is analyzed.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+/// Measuring a remote track downloads it a second time, which isn't worth it
+/// for anything longer than this.
+const MAX_REMOTE_DURATION: Duration = Duration::from_secs(20 * 60);
+
+#[derive(Serialize, Deserialize)]
+struct Measurement {
+    url: String,
+    lufs: f64,
+    measured: DateTime<Utc>,
+}
+
+/// The gain that brings audio measured at `lufs` to `target`.
+fn gain_for(lufs: f64, target: f64) -> f32 {
+    let db = (target - lufs).clamp(-MAX_CUT_DB, MAX_BOOST_DB);
+    10f64.powf(db / 20.0) as f32
+}
+
+/// URLs are too long and case sensitive to be store keys, so key by a hash.
+fn cache_key(url: &str) -> String {
+    // FNV-1a, which unlike `DefaultHasher` is stable between builds
+    let hash = url.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+async fn cached_lufs(ctx: &Context, url: &str) -> Option<f64> {
+    let measurement: Measurement = get_store(ctx).await.get(TABLE, &cache_key(url))?;
+    // Guard against hash collisions
+    (measurement.url == url).then_some(measurement.lufs)
+}
+
+async fn cache_lufs(ctx: &Context, url: &str, lufs: f64) {
+    let measurement = Measurement {
+        url: url.to_string(),
+        lufs,
+        measured: Utc::now(),
+    };
+    if let Err(e) = get_store(ctx)
+        .await
+        .put(TABLE, &cache_key(url), &measurement)
+    {
+        error!("Failed to cache loudness of {}: {:?}", url, e);
+    }
+}
+
+/// Sets up loudness normalization for a newly queued track if the guild has
+/// it turned on: a cached measurement is applied straight away, local files
+/// are measured right away, and everything else once it starts playing.
+/// Radio, live and very long remote tracks are left as they are, since
+/// measuring them would mean streaming them twice.
+pub async fn normalize(ctx: &Context, guild_id: GuildId, handle: &TrackHandle, info: &TrackInfo) {
+    let settings = guild_settings(ctx, guild_id).await;
+    if !settings.normalize {
+        return;
+    }
+
+    let target = settings.target_lufs as f64;
+
+    if let Some(lufs) = cached_lufs(ctx, &info.url).await {
+        set_track_gain(handle, gain_for(lufs, target)).await;
+        return;
+    }
+
+    // Local files are quick to read, so measure those up front
+    if info.is_local() {
+        spawn_analysis(ctx, handle, info, target);
+        return;
+    }
+
+    let too_long = info
+        .duration
+        .map_or(true, |duration| duration > MAX_REMOTE_DURATION);
+    if info.stream_title.is_some() || too_long {
+        info!("Not measuring loudness of {}", info.url);
+        return;
+    }
+
+    // Waiting for playback keeps a long queue from downloading everything at once
+    let _ = handle.add_event(
+        Event::Track(songbird::TrackEvent::Play),
+        Analyzer {
+            ctx: ctx.clone(),
+            info: info.clone(),
+            target,
+        },
+    );
+}
+
+/// Measures a track's loudness the first time it plays.
+struct Analyzer {
+    ctx: Context,
+    info: TrackInfo,
+    target: f64,
+}
+
+#[async_trait]
+impl EventHandler for Analyzer {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let EventContext::Track(tracks) = ctx else {
+            return None;
+        };
+
+        for (_, handle) in *tracks {
+            spawn_analysis(&self.ctx, handle, &self.info, self.target);
+        }
+
+        // Pausing and resuming fires Play again, but once is enough
+        Some(Event::Cancel)
+    }
+}
+
+fn spawn_analysis(ctx: &Context, handle: &TrackHandle, info: &TrackInfo, target: f64) {
+    let (ctx, handle, info) = (ctx.clone(), handle.clone(), info.clone());
+
+    tokio::spawn(async move {
+        if let Err(e) = analyze(&ctx, &handle, &info, target).await {
+            warn!("Couldn't measure loudness of {}: {:?}", info.url, e);
+        }
+    });
+}
+
+/// Decodes a second copy of the track alongside playback, adjusting its gain
+/// as the measurement firms up.
+async fn analyze(
+    ctx: &Context,
+    handle: &TrackHandle,
+    info: &TrackInfo,
+    target: f64,
+) -> Result<(), Error> {
+    let input = info
+        .input(ctx)
+        .await
+        .make_playable_async(&CODEC_REGISTRY, &PROBE)
+        .await?;
+    let Input::Live(LiveInput::Parsed(parsed), _) = input else {
+        return Err(Error::msg("input wasn't parsed"));
+    };
+
+    info!("Measuring loudness of {}", info.url);
+
+    let (tx, mut rx) = mpsc::channel(4);
+    let decoder = tokio::task::spawn_blocking(move || {
+        let mut format = parsed.format;
+        let mut decoder = parsed.decoder;
+        let mut meter: Option<Meter> = None;
+        let mut since_update = 0;
+        let mut samples: Option<SampleBuffer<f32>> = None;
+
+        while let Ok(packet) = format.next_packet() {
+            if packet.track_id() != parsed.track_id {
+                continue;
+            }
+            let Ok(decoded) = decoder.decode(&packet) else {
+                continue;
+            };
+
+            let spec = *decoded.spec();
+            let frames = decoded.frames();
+            let needed = decoded.capacity() * spec.channels.count();
+            if samples.as_ref().map_or(true, |buf| buf.capacity() < needed) {
+                samples = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+            }
+            let Some(buf) = samples.as_mut() else {
+                continue;
+            };
+            buf.copy_interleaved_ref(decoded);
+
+            let meter = meter.get_or_insert_with(|| Meter::new(spec.rate, spec.channels.count()));
+            meter.push(buf.samples());
+
+            since_update += frames;
+            if since_update as u64 >= spec.rate as u64 * UPDATE_INTERVAL.as_secs() {
+                since_update = 0;
+                if let Some(lufs) = meter.loudness() {
+                    // The track has ended, so stop measuring
+                    if tx.blocking_send(lufs).is_err() {
+                        return None;
+                    }
+                }
+            }
+        }
+
+        meter.and_then(|meter| meter.loudness())
+    });
+
+    while let Some(lufs) = rx.recv().await {
+        if handle.get_info().await.is_err() {
+            return Ok(());
+        }
+        set_track_gain(handle, gain_for(lufs, target)).await;
+    }
+
+    if let Some(lufs) = decoder.await? {
+        info!("{} measured at {:.1} LUFS", info.url, lufs);
+        set_track_gain(handle, gain_for(lufs, target)).await;
+        cache_lufs(ctx, &info.url, lufs).await;
+    }
+
+    Ok(())
+}
+
+/// Second order IIR filter.
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y;
+        y
+    }
+}
+
+/// The two stage K-weighting filter from ITU-R BS.1770, derived for any
+/// sample rate.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = rate as f64;
+
+    // High shelf modelling the head
+    let f0 = 1681.974450955533;
+    let g = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(g / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    // High pass
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+/// EBU R128 loudness meter: K-weighted mean square over 400 ms blocks every
+/// 100 ms, gated at -70 LUFS and then 10 LU below the ungated loudness.
+struct Meter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    /// Samples per channel in a 100 ms step
+    step_len: usize,
+    step_pos: usize,
+    step_energy: f64,
+    /// The last four steps, making up the current block
+    steps: VecDeque<f64>,
+    /// Mean square energy of each block
+    blocks: Vec<f64>,
+}
+
+impl Meter {
+    fn new(rate: u32, channels: usize) -> Self {
+        Self {
+            channels,
+            filters: vec![k_weighting(rate); channels],
+            step_len: (rate / 10) as usize,
+            step_pos: 0,
+            step_energy: 0.0,
+            steps: VecDeque::with_capacity(4),
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Adds interleaved samples.
+    fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (sample, filters) in frame.iter().zip(&mut self.filters) {
+                let y = filters
+                    .iter_mut()
+                    .fold(*sample as f64, |x, filter| filter.process(x));
+                self.step_energy += y * y;
+            }
+
+            self.step_pos += 1;
+            if self.step_pos == self.step_len {
+                self.end_step();
+            }
+        }
+    }
+
+    fn end_step(&mut self) {
+        if self.steps.len() == 4 {
+            self.steps.pop_front();
+        }
+        self.steps
+            .push_back(self.step_energy / self.step_len as f64);
+        self.step_pos = 0;
+        self.step_energy = 0.0;
+
+        if self.steps.len() == 4 {
+            self.blocks.push(self.steps.iter().sum::<f64>() / 4.0);
+        }
+    }
+
+    /// Integrated loudness in LUFS, or `None` if everything so far is silence.
+    fn loudness(&self) -> Option<f64> {
+        let lufs = |energy: f64| -0.691 + 10.0 * energy.log10();
+        let mean = |blocks: &[f64]| {
+            (!blocks.is_empty()).then(|| blocks.iter().sum::<f64>() / blocks.len() as f64)
+        };
+
+        let audible: Vec<f64> = self
+            .blocks
+            .iter()
+            .copied()
+            .filter(|energy| lufs(*energy) > -70.0)
+            .collect();
+        let threshold = lufs(mean(&audible)?) - 10.0;
+
+        let gated: Vec<f64> = audible
+            .into_iter()
+            .filter(|energy| lufs(*energy) > threshold)
+            .collect();
+        mean(&gated).map(lufs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(amplitude: f32, secs: usize) -> Vec<f32> {
+        (0..48_000 * secs)
+            .map(|i| amplitude * (2.0 * PI * 1000.0 * i as f64 / 48_000.0).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn measures_a_1khz_sine() {
+        // A full scale 1 kHz sine in one channel is -3.01 LUFS by definition
+        let mut meter = Meter::new(48_000, 1);
+        meter.push(&sine(0.1, 5));
+
+        let lufs = meter.loudness().unwrap();
+        assert!((lufs - -23.01).abs() < 0.5, "{lufs}");
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let mut meter = Meter::new(48_000, 2);
+        meter.push(&vec![0.0; 48_000 * 2]);
+
+        assert_eq!(meter.loudness(), None);
+    }
+}
@@ -2,36 +2,88 @@ extern crate dotenv;
 
 mod bot;
 mod cfg;
+mod consent;
+mod controls;
+mod crossfade;
+mod ducking;
+mod encryption;
 mod history;
+mod idle;
+mod library;
 mod logging;
+mod loudness;
 mod message;
 mod music;
 mod openai;
+mod playback;
+mod playlists;
+mod radio;
+mod retention;
+mod search;
+mod session;
+mod settings;
+mod shutdown;
+mod snapshot;
 mod state;
+mod store;
+mod summary;
+mod transcript;
+mod vad;
 mod voice;
 
 use std::collections::HashSet;
 use std::env;
+use std::sync::Arc;
 
 use chrono::Utc;
 use dotenv::dotenv;
 use log::{error, info};
 use serenity::async_trait;
-use serenity::framework::standard::macros::group;
-use serenity::framework::standard::{Configuration, StandardFramework};
+use serenity::framework::standard::macros::{group, hook};
+use serenity::framework::standard::{Configuration, DispatchError, StandardFramework};
 use serenity::http::Http;
 use serenity::model::channel::Message;
 use serenity::model::event::ResumedEvent;
 use serenity::model::gateway::Ready;
+use serenity::model::voice::VoiceState;
 use serenity::prelude::*;
 use songbird::driver::DecodeMode;
 use songbird::SerenityInit;
 
 use crate::bot::Bot;
-use crate::cfg::BOT_ID;
+use crate::cfg::{BOT_ID, DATA_DIR};
+use crate::consent::*;
+use crate::controls::*;
+use crate::history::{History, HISTORY_KEY, HISTORY_TABLE};
+use crate::idle::update_listeners;
+use crate::library::*;
 use crate::logging::setup_logging;
 use crate::music::*;
-use crate::state::{HttpKey, ShardManagerContainer};
+use crate::playlists::*;
+use crate::retention::*;
+use crate::search::provider_from_env;
+use crate::session::*;
+use crate::settings::*;
+use crate::shutdown::{accept_command, is_shutting_down, shutdown, wait_for_signal};
+use crate::snapshot::*;
+use crate::state::{
+    ConsentKey, GuildLockKey, HttpKey, IdleKey, LibraryKey, LoopModeKey, RecordingKey, SearchKey,
+    SessionKey, ShardManagerContainer, ShutdownKey, StoreKey,
+};
+use crate::store::Store;
+use crate::summary::*;
+use crate::voice::follow_move;
+
+/// Tells people why a command they aren't allowed to use did nothing.
+#[hook]
+async fn dispatch_error(ctx: &Context, msg: &Message, error: DispatchError, _command_name: &str) {
+    if let DispatchError::LackingPermissions(_) | DispatchError::OnlyForOwners = error {
+        let _ = msg
+            .channel_id
+            .say(&ctx.http, "You don't have permission to do that.")
+            .await;
+    }
+}
 
 #[async_trait]
 impl EventHandler for Bot {
@@ -42,6 +94,10 @@ impl EventHandler for Bot {
             return;
         }
 
+        if is_shutting_down(&ctx).await {
+            return;
+        }
+
         if let Ok(mut user_limits) = self.user_limits.lock() {
             if let Some((last_time, count)) = user_limits.get(&msg.author.id.into()) {
                 let current_time = Utc::now().timestamp();
@@ -99,17 +155,69 @@ impl EventHandler for Bot {
         }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
+
+        let _ = self.context.set(ctx.clone());
+
+        // This needs a context, so it can only start once there is one
+        self.background.call_once(|| {
+            tokio::spawn(async move {
+                loop {
+                    enforce(&ctx).await;
+                    tokio::time::sleep(RETENTION_INTERVAL).await;
+                }
+            });
+        });
     }
 
     async fn resume(&self, _: Context, _: ResumedEvent) {
         info!("Resumed");
     }
+
+    async fn voice_state_update(&self, ctx: Context, _old: Option<VoiceState>, new: VoiceState) {
+        let Some(guild_id) = new.guild_id else {
+            return;
+        };
+
+        if new.user_id == ctx.cache.current_user().id {
+            follow_move(&ctx, guild_id, new.channel_id).await;
+        }
+        update_listeners(&ctx, guild_id).await;
+    }
 }
 
 #[group]
-#[commands(queue, skip, stop, vol)]
+#[commands(
+    queue,
+    search,
+    skip,
+    stop,
+    vol,
+    np,
+    list,
+    pause,
+    resume,
+    seek,
+    repeat,
+    shuffle,
+    remove,
+    move_track,
+    clear,
+    playlist,
+    library,
+    local,
+    restore,
+    recap,
+    sessions,
+    recordings_list,
+    pin,
+    unpin,
+    optout,
+    optin,
+    settings,
+    set
+)]
 struct General;
 
 #[tokio::main]
@@ -120,6 +228,31 @@ async fn main() {
 
     setup_logging();
 
+    // `adam decrypt` and `adam keygen` work on recordings without going online
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("decrypt") => {
+            if let Err(e) = encryption::decrypt_command(&args[1..]) {
+                error!("{:?}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some("keygen") => {
+            println!("{}", encryption::generate_key());
+            return;
+        }
+        _ => {}
+    }
+
+    let recording_key = match encryption::recording_key() {
+        Ok(key) => key,
+        Err(e) => panic!("Invalid recording key: {:?}", e),
+    };
+    if recording_key.is_some() {
+        info!("Recordings will be encrypted at rest");
+    }
+
     let token = env::var("DISCORD_TOKEN").expect("'DISCORD_TOKEN' not found");
     let intents = GatewayIntents::non_privileged()
         | GatewayIntents::MESSAGE_CONTENT
@@ -138,7 +271,10 @@ async fn main() {
         Err(error) => panic!("Could not access application info: {:?}", error),
     };
 
-    let framework = StandardFramework::new().group(&GENERAL_GROUP);
+    let framework = StandardFramework::new()
+        .group(&GENERAL_GROUP)
+        .before(accept_command)
+        .on_dispatch_error(dispatch_error);
     framework.configure(Configuration::new().owners(owners).prefix("~"));
 
     let yt_client = reqwest::Client::new();
@@ -148,25 +284,55 @@ async fn main() {
         // .playout_spike_length(NonZeroUsize::new(16).unwrap().into())
         ;
 
+    let store = Arc::new(Store::new(DATA_DIR));
+    let bot = Bot::new();
+    if let Some(history) = store.get::<History>(HISTORY_TABLE, HISTORY_KEY) {
+        if let Ok(mut saved) = bot.history.lock() {
+            *saved = history;
+        }
+    }
+
+    let signal_bot = bot.clone();
+
     let mut client = Client::builder(token, intents)
-        .event_handler(Bot::new())
+        .event_handler(bot)
         .framework(framework)
         .register_songbird_from_config(songbird_cfg)
+        .type_map_insert::<SearchKey>(provider_from_env(yt_client.clone()))
         .type_map_insert::<HttpKey>(yt_client)
+        .type_map_insert::<SessionKey>(Default::default())
+        .type_map_insert::<ConsentKey>(Arc::new(Consent::load(&store)))
+        .type_map_insert::<StoreKey>(store)
+        .type_map_insert::<LoopModeKey>(Default::default())
+        .type_map_insert::<IdleKey>(Default::default())
+        .type_map_insert::<ShutdownKey>(Default::default())
+        .type_map_insert::<GuildLockKey>(Default::default())
+        .type_map_insert::<RecordingKey>(recording_key)
         .await
         .expect("Error creating client");
 
     {
         let mut data = client.data.write().await;
         data.insert::<ShardManagerContainer>(client.shard_manager.clone());
+
+        if let Some(library) = Library::from_env() {
+            data.insert::<LibraryKey>(library.clone());
+            tokio::task::spawn_blocking(move || library.scan());
+        }
     }
 
+    // Installed before connecting, so a signal that arrives early still shuts
+    // down cleanly
     let shard_manager = client.shard_manager.clone();
     tokio::spawn(async move {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Could not register ctrl+c handler");
-        shard_manager.shutdown_all().await;
+        wait_for_signal().await;
+        match signal_bot.context.get() {
+            Some(ctx) => shutdown(ctx, &signal_bot.history).await,
+            None => {
+                info!("Shutting down before connecting");
+                shard_manager.shutdown_all().await;
+            }
+        }
     });
 
     if let Err(error) = client.start().await {
@@ -1,15 +1,247 @@
-use std::env;
+use std::sync::Arc;
+use std::time::Duration;
 
+use anyhow::Error;
 use log::{error, info};
 use reqwest::Client as HttpClient;
-use reqwest::Error;
+use serenity::all::{ComponentInteractionDataKind, GuildId, UserId};
+use serenity::builder::{
+    CreateEmbed, CreateEmbedFooter, CreateInteractionResponse, CreateMessage, CreateSelectMenu,
+    CreateSelectMenuKind, CreateSelectMenuOption, EditInteractionResponse,
+};
 use serenity::client::Context;
 use serenity::framework::standard::macros::command;
 use serenity::framework::standard::{Args, CommandResult};
 use serenity::model::channel::Message;
-use songbird::input::YoutubeDl;
+use songbird::input::{AuxMetadata, Compose, File, Input, YoutubeDl};
+use songbird::tracks::TrackHandle;
+use songbird::typemap::{TypeMap, TypeMapKey};
+use songbird::Call;
+use tokio::sync::Mutex;
+
+use crate::crossfade;
+use crate::loudness::normalize;
+use crate::radio::{probe, Radio, StreamTitle};
+use crate::search::{expand_playlist, is_playlist, SearchProvider, SearchResult};
+use crate::settings::{guild_settings, update_guild_settings, MAX_VOLUME_PCT};
+use crate::state::{HttpKey, SearchKey};
+
+/// Track volume at 100%.
+pub const MUSIC_VOLUME: f32 = 0.05;
+
+/// The volume the guild has set with `~vol`.
+pub async fn guild_volume(ctx: &Context, guild_id: GuildId) -> f32 {
+    let volume_pct = guild_settings(ctx, guild_id).await.volume_pct;
+    MUSIC_VOLUME * volume_pct as f32 / 100.0
+}
+
+/// The volume a queued track should play at when it isn't being ducked.
+pub struct TrackVolume;
+
+impl TypeMapKey for TrackVolume {
+    type Value = f32;
+}
+
+/// Loudness normalization gain, applied on top of the track's volume.
+pub struct TrackGain;
+
+impl TypeMapKey for TrackGain {
+    type Value = f32;
+}
+
+/// How far a crossfade has faded the track in or out.
+pub struct TrackFade;
+
+impl TypeMapKey for TrackFade {
+    type Value = f32;
+}
+
+fn mixed_volume(typemap: &TypeMap) -> f32 {
+    let volume = typemap
+        .get::<TrackVolume>()
+        .copied()
+        .unwrap_or(MUSIC_VOLUME);
+    let gain = typemap.get::<TrackGain>().copied().unwrap_or(1.0);
+    let fade = typemap.get::<TrackFade>().copied().unwrap_or(1.0);
+
+    volume * gain * fade
+}
+
+pub async fn set_track_volume(track: &TrackHandle, volume: f32) {
+    let mut typemap = track.typemap().write().await;
+    typemap.insert::<TrackVolume>(volume);
+    let _ = track.set_volume(mixed_volume(&typemap));
+}
+
+pub async fn set_track_gain(track: &TrackHandle, gain: f32) {
+    let mut typemap = track.typemap().write().await;
+    typemap.insert::<TrackGain>(gain);
+    let _ = track.set_volume(mixed_volume(&typemap));
+}
+
+pub async fn set_track_fade(track: &TrackHandle, fade: f32) {
+    let mut typemap = track.typemap().write().await;
+    typemap.insert::<TrackFade>(fade);
+    let _ = track.set_volume(mixed_volume(&typemap));
+}
+
+/// The volume the track plays at when it isn't being ducked.
+pub async fn get_track_volume(track: &TrackHandle) -> f32 {
+    mixed_volume(&*track.typemap().read().await)
+}
+
+/// Shown by `~np` and `~list`, gathered when the track is queued.
+#[derive(Clone, Debug)]
+pub struct TrackInfo {
+    pub title: String,
+    pub url: String,
+    pub artist: Option<String>,
+    pub duration: Option<Duration>,
+    pub thumbnail: Option<String>,
+    pub requester: Option<UserId>,
+    /// Set for live radio streams, which have no fixed title
+    pub stream_title: Option<StreamTitle>,
+}
+
+impl TypeMapKey for TrackInfo {
+    type Value = TrackInfo;
+}
+
+impl TrackInfo {
+    /// Looks up the source's metadata. Do this before locking the call, since
+    /// for `YoutubeDl` it means running yt-dlp.
+    pub async fn fetch<S: Compose>(source: &mut S, url: &str, requester: Option<UserId>) -> Self {
+        Self::try_fetch(source, url, requester)
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to get metadata for {}: {:?}", url, e);
+                Self::from_metadata(Default::default(), url, requester)
+            })
+    }
+
+    /// Like `fetch`, but fails if the source can't be resolved, e.g. for a
+    /// deleted video.
+    pub async fn try_fetch<S: Compose>(
+        source: &mut S,
+        url: &str,
+        requester: Option<UserId>,
+    ) -> Result<Self, Error> {
+        let metadata = source.aux_metadata().await?;
+        Ok(Self::from_metadata(metadata, url, requester))
+    }
+
+    fn from_metadata(metadata: AuxMetadata, url: &str, requester: Option<UserId>) -> Self {
+        Self {
+            title: metadata
+                .title
+                .or(metadata.track)
+                .unwrap_or_else(|| url.to_string()),
+            url: metadata.source_url.unwrap_or_else(|| url.to_string()),
+            artist: metadata.artist.or(metadata.channel),
+            duration: metadata.duration,
+            thumbnail: metadata.thumbnail,
+            requester,
+            stream_title: None,
+        }
+    }
+
+    /// Uses what a search or playlist listing already told us, so the track
+    /// doesn't have to be resolved until it plays.
+    pub fn from_result(result: SearchResult, requester: Option<UserId>) -> Self {
+        Self {
+            title: result.title,
+            url: result.url,
+            artist: Some(result.channel).filter(|channel| !channel.is_empty()),
+            duration: result.duration,
+            thumbnail: None,
+            requester,
+            stream_title: None,
+        }
+    }
 
-use crate::state::HttpKey;
+    pub fn radio(url: &str, name: Option<String>, requester: Option<UserId>) -> Self {
+        Self {
+            title: name.unwrap_or_else(|| url.to_string()),
+            url: url.to_string(),
+            artist: None,
+            duration: None,
+            thumbnail: None,
+            requester,
+            stream_title: Some(Default::default()),
+        }
+    }
+
+    /// Whether this track plays from a local file rather than a URL.
+    pub fn is_local(&self) -> bool {
+        !self.url.starts_with("http://") && !self.url.starts_with("https://")
+    }
+
+    /// Recreates a playable input for this track, e.g. to queue it again.
+    pub async fn input(&self, ctx: &Context) -> Input {
+        if self.is_local() {
+            File::new(self.url.clone()).into()
+        } else if let Some(title) = &self.stream_title {
+            Radio::new(self.url.clone(), title.clone()).into()
+        } else {
+            YoutubeDl::new(get_http_client(ctx).await, self.url.clone()).into()
+        }
+    }
+
+    /// `[title](url) 3:45 - @requester`
+    pub fn line(&self) -> String {
+        let title = self.title.replace(['[', ']'], "");
+        let mut line = if self.is_local() {
+            format!("**{title}**")
+        } else {
+            format!("[{title}]({})", self.url)
+        };
+        if let Some(duration) = self.duration {
+            line.push_str(&format!(" `{}`", format_duration(duration)));
+        }
+        if let Some(requester) = self.requester {
+            line.push_str(&format!(" - <@{requester}>"));
+        }
+        line
+    }
+}
+
+pub async fn get_track_info(track: &TrackHandle) -> Option<TrackInfo> {
+    track.typemap().read().await.get::<TrackInfo>().cloned()
+}
+
+/// Adds a track to the end of the queue along with its metadata, at the
+/// guild's volume.
+pub async fn enqueue(
+    ctx: &Context,
+    guild_id: GuildId,
+    call: &mut Call,
+    input: Input,
+    info: TrackInfo,
+) -> TrackHandle {
+    let volume = guild_volume(ctx, guild_id).await;
+    let fade = Duration::from_millis(guild_settings(ctx, guild_id).await.crossfade_ms);
+
+    // Use lazy restartable sources to make sure that we don't pay
+    // for decoding, playback on tracks which aren't actually live yet.
+    // The next track is readied before this one ends so there's no gap.
+    let handle =
+        call.enqueue_with_preload(input.into(), crossfade::preload_time(info.duration, fade));
+    set_track_volume(&handle, volume).await;
+    crossfade::schedule(ctx, guild_id, &handle, info.duration, fade);
+    normalize(ctx, guild_id, &handle, &info).await;
+    handle.typemap().write().await.insert::<TrackInfo>(info);
+
+    handle
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
 
 #[command]
 #[only_in(guilds)]
@@ -23,22 +255,44 @@ pub async fn queue(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
     let manager = songbird::get(ctx).await.unwrap().clone();
 
     if let Some(handler_lock) = manager.get(guild_id) {
-        let mut handler = handler_lock.lock().await;
-
-        let (youtube_dl, url) = find_song(&ctx, search).await?;
+        let urls: Vec<&str> = search.split_whitespace().collect();
+        let all_urls = urls
+            .iter()
+            .all(|url| url.starts_with("https://") || url.starts_with("http://"));
+        if !urls.is_empty() && all_urls {
+            queue_urls(ctx, msg, &handler_lock, &urls).await;
+            return Ok(());
+        }
+
+        let (mut youtube_dl, url) = match find_song(ctx, search).await {
+            Ok(song) => song,
+            Err(e) => {
+                error!("Search error: {:?}", e);
+                let _ = msg
+                    .channel_id
+                    .say(&ctx.http, format!("Couldn't queue that: {e}"))
+                    .await;
+                return Ok(());
+            }
+        };
 
         info!("Queueing {}", url);
 
-        // Use lazy restartable sources to make sure that we don't pay
-        // for decoding, playback on tracks which aren't actually live yet.
-        let handle = handler.enqueue_input(youtube_dl.into()).await;
-        let _ = handle.set_volume(0.05);
+        let info = TrackInfo::fetch(&mut youtube_dl, &url, Some(msg.author.id)).await;
+        let title = info.title.clone();
+
+        let mut handler = handler_lock.lock().await;
+        enqueue(ctx, guild_id, &mut handler, youtube_dl.into(), info).await;
 
         let _ = msg
             .channel_id
             .say(
                 &ctx.http,
-                format!("Added song to queue: position {}", handler.queue().len()),
+                format!(
+                    "Added **{}** to queue: position {}",
+                    title,
+                    handler.queue().len()
+                ),
             )
             .await;
     } else {
@@ -48,6 +302,74 @@ pub async fn queue(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
     Ok(())
 }
 
+/// Queues every URL given to `~queue`, expanding playlists into their
+/// individual tracks and playing live streams directly, and replies with how
+/// many made it in.
+async fn queue_urls(ctx: &Context, msg: &Message, call: &Mutex<Call>, urls: &[&str]) {
+    let guild_id = msg.guild_id.unwrap();
+    let limit = guild_settings(ctx, guild_id).await.playlist_limit;
+    let client = get_http_client(ctx).await;
+    let requester = Some(msg.author.id);
+
+    let mut tracks: Vec<(Input, TrackInfo)> = Vec::new();
+    let mut failed = 0;
+
+    for url in urls {
+        if is_playlist(url) {
+            info!("Expanding playlist {}", url);
+
+            match expand_playlist(url, limit).await {
+                Ok(entries) if !entries.is_empty() => {
+                    for entry in entries {
+                        let input = YoutubeDl::new(client.clone(), entry.url.clone()).into();
+                        tracks.push((input, TrackInfo::from_result(entry, requester)));
+                    }
+                }
+                Ok(_) => failed += 1,
+                Err(e) => {
+                    error!("Failed to expand playlist {}: {:?}", url, e);
+                    failed += 1;
+                }
+            }
+        } else if let Some(station) = probe(url).await {
+            info!("Queueing radio stream {}", station.url);
+
+            let info = TrackInfo::radio(&station.url, station.name, requester);
+            tracks.push((info.input(ctx).await, info));
+        } else {
+            let mut youtube_dl = YoutubeDl::new(client.clone(), url.to_string());
+
+            match TrackInfo::try_fetch(&mut youtube_dl, url, requester).await {
+                Ok(info) => tracks.push((youtube_dl.into(), info)),
+                Err(e) => {
+                    error!("Failed to get metadata for {}: {:?}", url, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    let added = tracks.len();
+    let first_title = tracks.first().map(|(_, info)| info.title.clone());
+    let position = {
+        let mut handler = call.lock().await;
+        for (input, info) in tracks {
+            enqueue(ctx, guild_id, &mut handler, input, info).await;
+        }
+        handler.queue().len()
+    };
+
+    let mut reply = match (added, first_title) {
+        (0, _) => "Couldn't queue any of those".to_string(),
+        (1, Some(title)) => format!("Added **{title}** to queue: position {position}"),
+        (n, _) => format!("Added {n} tracks to the queue"),
+    };
+    if failed > 0 {
+        reply.push_str(&format!(" ({failed} failed)"));
+    }
+    let _ = msg.channel_id.say(&ctx.http, reply).await;
+}
+
 #[command]
 #[only_in(guilds)]
 pub async fn skip(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
@@ -101,34 +423,232 @@ pub async fn stop(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
 #[command]
 #[only_in(guilds)]
 pub async fn vol(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    let volume = args.message().parse::<f32>().unwrap() / 100.0;
+    let guild_id = msg.guild_id.unwrap();
+    let arg = args.message().trim().trim_end_matches('%');
+
+    if arg.is_empty() {
+        let volume_pct = guild_settings(ctx, guild_id).await.volume_pct;
+        let _ = msg
+            .channel_id
+            .say(&ctx.http, format!("Volume is {volume_pct}%"))
+            .await;
+        return Ok(());
+    }
 
+    let volume_pct = match arg.parse::<u16>() {
+        Ok(pct) if pct <= MAX_VOLUME_PCT => pct,
+        _ => {
+            let _ = msg
+                .channel_id
+                .say(
+                    &ctx.http,
+                    format!("Volume must be a number from 0 to {MAX_VOLUME_PCT}"),
+                )
+                .await;
+            return Ok(());
+        }
+    };
+
+    if let Err(e) = update_guild_settings(ctx, guild_id, |settings| {
+        settings.volume_pct = volume_pct;
+        Ok(())
+    })
+    .await
+    {
+        let _ = msg
+            .channel_id
+            .say(&ctx.http, format!("Couldn't set the volume: {e}"))
+            .await;
+        return Ok(());
+    }
+
+    let manager = songbird::get(ctx).await.unwrap().clone();
+    if let Some(call) = manager.get(guild_id) {
+        let volume = guild_volume(ctx, guild_id).await;
+        let tracks = call.lock().await.queue().current_queue();
+        for track in &tracks {
+            set_track_volume(track, volume).await;
+        }
+    }
+
+    let _ = msg
+        .channel_id
+        .say(&ctx.http, format!("Volume set to {volume_pct}%"))
+        .await;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn np(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
     let guild_id = msg.guild_id.unwrap();
     let manager = songbird::get(ctx).await.unwrap().clone();
 
-    if let Some(call) = manager.get(guild_id) {
-        call.lock()
-            .await
-            .queue()
-            .current_queue()
-            .iter()
-            .for_each(|t| {
-                let _ = t.set_volume(volume);
-            });
-    } else {
-        error!("Not in a voice channel");
+    let current = match manager.get(guild_id) {
+        Some(call) => call.lock().await.queue().current(),
+        None => None,
+    };
+
+    let Some(track) = current else {
+        let _ = msg.channel_id.say(&ctx.http, "Nothing is playing.").await;
+        return Ok(());
+    };
+
+    let info = get_track_info(&track).await;
+    let position = track
+        .get_info()
+        .await
+        .map(|state| state.position)
+        .unwrap_or_default();
+
+    let mut embed = CreateEmbed::new().title("Now playing");
+
+    match info {
+        Some(info) => {
+            embed = embed.description(info.line());
+
+            if let Some(artist) = &info.artist {
+                embed = embed.field("Artist", artist, true);
+            }
+
+            if let Some(stream_title) = &info.stream_title {
+                let on_air = stream_title.lock().ok().and_then(|title| title.clone());
+                embed = embed.field(
+                    "On air",
+                    on_air.unwrap_or_else(|| "Unknown".to_string()),
+                    false,
+                );
+            }
+
+            let elapsed = format_duration(position);
+            embed = match info.duration {
+                Some(duration) => embed
+                    .field(
+                        "Elapsed",
+                        format!("{} / {}", elapsed, format_duration(duration)),
+                        true,
+                    )
+                    .field(
+                        "Remaining",
+                        format_duration(duration.saturating_sub(position)),
+                        true,
+                    ),
+                None => embed.field("Elapsed", elapsed, true),
+            };
+
+            if let Some(thumbnail) = info.thumbnail {
+                embed = embed.thumbnail(thumbnail);
+            }
+        }
+        None => {
+            embed = embed.field("Elapsed", format_duration(position), true);
+        }
+    }
+
+    let _ = msg
+        .channel_id
+        .send_message(&ctx.http, CreateMessage::new().embed(embed))
+        .await;
+
+    Ok(())
+}
+
+/// Queue entries shown by `~list`, to stay within Discord's embed limits.
+const LIST_LIMIT: usize = 15;
+
+#[command]
+#[only_in(guilds)]
+pub async fn list(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let manager = songbird::get(ctx).await.unwrap().clone();
+
+    let tracks = match manager.get(guild_id) {
+        Some(call) => call.lock().await.queue().current_queue(),
+        None => Vec::new(),
+    };
+
+    if tracks.is_empty() {
+        let _ = msg.channel_id.say(&ctx.http, "The queue is empty.").await;
+        return Ok(());
+    }
+
+    let mut lines = Vec::new();
+    let mut total = Duration::ZERO;
+
+    for (i, track) in tracks.iter().enumerate() {
+        let info = get_track_info(track).await;
+        total += info.as_ref().and_then(|i| i.duration).unwrap_or_default();
+
+        if i >= LIST_LIMIT {
+            continue;
+        }
+
+        let line = info
+            .map(|i| i.line())
+            .unwrap_or_else(|| "Unknown".to_string());
+        if i == 0 {
+            let position = track
+                .get_info()
+                .await
+                .map(|state| state.position)
+                .unwrap_or_default();
+            lines.push(format!(
+                "**Now playing** ({}): {}",
+                format_duration(position),
+                line
+            ));
+        } else {
+            lines.push(format!("**{i}.** {line}"));
+        }
+    }
+
+    if tracks.len() > LIST_LIMIT {
+        lines.push(format!("...and {} more", tracks.len() - LIST_LIMIT));
     }
 
+    let embed = CreateEmbed::new()
+        .title("Queue")
+        .description(lines.join("\n"))
+        .footer(CreateEmbedFooter::new(format!(
+            "{} tracks, {} total",
+            tracks.len(),
+            format_duration(total)
+        )));
+
+    let _ = msg
+        .channel_id
+        .send_message(&ctx.http, CreateMessage::new().embed(embed))
+        .await;
+
     Ok(())
 }
 
-async fn get_http_client(ctx: &Context) -> HttpClient {
+pub async fn get_http_client(ctx: &Context) -> HttpClient {
     let data = ctx.data.read().await;
     data.get::<HttpKey>()
         .cloned()
         .expect("Http client not found")
 }
 
+async fn get_search_provider(ctx: &Context) -> Arc<dyn SearchProvider> {
+    let data = ctx.data.read().await;
+    data.get::<SearchKey>()
+        .cloned()
+        .expect("Search provider not found")
+}
+
+pub async fn search_songs(
+    ctx: &Context,
+    search: &str,
+    max_results: usize,
+) -> Result<Vec<SearchResult>, Error> {
+    get_search_provider(ctx)
+        .await
+        .search(search, max_results)
+        .await
+}
+
 pub async fn find_song(ctx: &Context, search: &str) -> Result<(YoutubeDl, String), Error> {
     let client = get_http_client(ctx).await;
 
@@ -137,27 +657,142 @@ pub async fn find_song(ctx: &Context, search: &str) -> Result<(YoutubeDl, String
         return Ok((youtube_dl, search.to_string()));
     }
 
-    let yt_api_key = env::var("YOUTUBE_API_KEY").expect("YOUTUBE_API_KEY not set");
+    let Some(result) = search_songs(ctx, search, 1).await?.into_iter().next() else {
+        return Err(Error::msg(format!("no results for \"{search}\"")));
+    };
+
+    let youtube_dl = YoutubeDl::new(client, result.url.clone());
+
+    Ok((youtube_dl, result.url))
+}
+
+/// How many results `~search` offers.
+const SEARCH_RESULTS: usize = 5;
+
+/// How long the requester has to pick a search result.
+const PICK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[command]
+#[only_in(guilds)]
+pub async fn search(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let search = args.message();
+    let guild_id = msg.guild_id.unwrap();
 
-    let search_results = client
-        .get("https://www.googleapis.com/youtube/v3/search")
-        .query(&[
-            ("key", yt_api_key.as_str()),
-            ("type", "video"),
-            ("maxResults", "1"),
-            ("q", search),
-        ])
-        .send()
+    let manager = songbird::get(ctx).await.unwrap().clone();
+    let Some(handler_lock) = manager.get(guild_id) else {
+        error!("Not in a voice channel");
+        return Ok(());
+    };
+
+    let results = match search_songs(ctx, search, SEARCH_RESULTS).await {
+        Ok(results) if !results.is_empty() => results,
+        Ok(_) => {
+            let _ = msg
+                .channel_id
+                .say(
+                    &ctx.http,
+                    format!("Couldn't find anything for \"{search}\"."),
+                )
+                .await;
+            return Ok(());
+        }
+        Err(e) => {
+            error!("Search error: {:?}", e);
+            let _ = msg.channel_id.say(&ctx.http, "Search failed.").await;
+            return Ok(());
+        }
+    };
+
+    let options = results
+        .iter()
+        .enumerate()
+        .map(|(i, result)| {
+            let duration = result.duration.map(format_duration).unwrap_or_default();
+            CreateSelectMenuOption::new(truncate(&result.title, 100), i.to_string())
+                .description(truncate(&format!("{} {}", result.channel, duration), 100))
+        })
+        .collect();
+
+    let menu = CreateSelectMenu::new("search", CreateSelectMenuKind::String { options })
+        .placeholder("Pick a song");
+
+    let prompt = msg
+        .channel_id
+        .send_message(
+            &ctx.http,
+            CreateMessage::new()
+                .content(format!("Results for \"{search}\":"))
+                .select_menu(menu),
+        )
         .await?;
-    let search_results = search_results.json::<serde_json::Value>().await?;
 
-    let video_id = search_results["items"][0]["id"]["videoId"]
-        .as_str()
-        .expect("No video found")
-        .to_string();
+    let Some(interaction) = prompt
+        .await_component_interaction(&ctx.shard)
+        .author_id(msg.author.id)
+        .timeout(PICK_TIMEOUT)
+        .await
+    else {
+        let _ = prompt.delete(&ctx.http).await;
+        let _ = msg.channel_id.say(&ctx.http, "No song picked.").await;
+        return Ok(());
+    };
+
+    // yt-dlp and loudness analysis can take longer than Discord waits for an
+    // answer, so acknowledge the pick now and fill in the result after
+    let _ = interaction
+        .create_response(&ctx.http, CreateInteractionResponse::Acknowledge)
+        .await;
+
+    let picked = match &interaction.data.kind {
+        ComponentInteractionDataKind::StringSelect { values } => values
+            .first()
+            .and_then(|value| value.parse::<usize>().ok())
+            .and_then(|i| results.get(i)),
+        _ => None,
+    };
+
+    let Some(picked) = picked else {
+        let _ = interaction
+            .edit_response(
+                &ctx.http,
+                EditInteractionResponse::new()
+                    .content("No song picked.")
+                    .components(Vec::new()),
+            )
+            .await;
+        return Ok(());
+    };
 
-    let url = format!("https://www.youtube.com/watch?v={}", video_id);
-    let youtube_dl = YoutubeDl::new(client, url.clone());
+    info!("Queueing {}", picked.url);
+
+    let mut youtube_dl = YoutubeDl::new(get_http_client(ctx).await, picked.url.clone());
+    let info = TrackInfo::fetch(&mut youtube_dl, &picked.url, Some(msg.author.id)).await;
+    let title = info.title.clone();
+
+    let position = {
+        let mut handler = handler_lock.lock().await;
+        enqueue(ctx, guild_id, &mut handler, youtube_dl.into(), info).await;
+        handler.queue().len()
+    };
+
+    let _ = interaction
+        .edit_response(
+            &ctx.http,
+            EditInteractionResponse::new()
+                .content(format!("Added **{title}** to queue: position {position}"))
+                .components(Vec::new()),
+        )
+        .await;
+
+    Ok(())
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
 
-    Ok((youtube_dl, url))
+    let mut text: String = text.chars().take(max - 1).collect();
+    text.push('…');
+    text
 }
@@ -0,0 +1,70 @@
+use dashmap::DashSet;
+use serenity::async_trait;
+use songbird::tracks::PlayMode;
+use songbird::{Event, EventContext as Ctx, EventHandler, TrackEvent};
+use uuid::Uuid;
+
+/// Track events that change whether a track is audible.
+pub const PLAYBACK_EVENTS: [TrackEvent; 5] = [
+    TrackEvent::Play,
+    TrackEvent::Playable,
+    TrackEvent::Pause,
+    TrackEvent::End,
+    TrackEvent::Error,
+];
+
+/// Keeps track of what the bot is currently playing in a call, driven by
+/// songbird track events rather than estimated durations.
+#[derive(Default)]
+pub struct Playback {
+    playing: DashSet<Uuid>,
+    speech: DashSet<Uuid>,
+}
+
+impl Playback {
+    /// Marks a track as a spoken (TTS) reply rather than music.
+    pub fn add_speech(&self, uuid: Uuid) {
+        self.speech.insert(uuid);
+    }
+
+    /// Whether any bot audio (TTS or music) is playing.
+    pub fn is_active(&self) -> bool {
+        !self.playing.is_empty()
+    }
+
+    /// Whether a TTS reply is playing.
+    pub fn is_speaking(&self) -> bool {
+        self.playing.iter().any(|uuid| self.speech.contains(&*uuid))
+    }
+
+    fn update(&self, uuid: Uuid, mode: &PlayMode) {
+        match mode {
+            PlayMode::Play => {
+                self.playing.insert(uuid);
+            }
+            PlayMode::Pause => {
+                self.playing.remove(&uuid);
+            }
+            _ => {
+                self.playing.remove(&uuid);
+                self.speech.remove(&uuid);
+            }
+        }
+    }
+}
+
+/// Global track event handler feeding [`Playback`].
+pub struct PlaybackMonitor(pub std::sync::Arc<Playback>);
+
+#[async_trait]
+impl EventHandler for PlaybackMonitor {
+    async fn act(&self, ctx: &Ctx<'_>) -> Option<Event> {
+        if let Ctx::Track(tracks) = ctx {
+            for (state, handle) in *tracks {
+                self.0.update(handle.uuid(), &state.playing);
+            }
+        }
+
+        None
+    }
+}
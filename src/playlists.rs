@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serenity::all::{GuildId, UserId};
+use serenity::builder::{CreateEmbed, CreateEmbedFooter, CreateMessage};
+use serenity::client::Context;
+use serenity::framework::standard::macros::command;
+use serenity::framework::standard::{Args, CommandResult};
+use serenity::model::channel::Message;
+use songbird::input::YoutubeDl;
+
+use crate::controls::caller_call;
+use crate::music::{enqueue, format_duration, get_http_client, get_track_info, TrackInfo};
+use crate::store::{get_store, guild_lock, Store};
+
+const TABLE: &str = "playlists";
+
+/// Most tracks shown by `~playlist list <name>`.
+const LIST_LIMIT: usize = 15;
+
+/// A playlist entry. `url` is either a URL or a path to a local file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedTrack {
+    pub title: String,
+    pub url: String,
+    pub artist: Option<String>,
+    pub duration: Option<Duration>,
+    /// Radio stream rather than a single track
+    #[serde(default)]
+    pub live: bool,
+}
+
+impl From<TrackInfo> for SavedTrack {
+    fn from(info: TrackInfo) -> Self {
+        Self {
+            title: info.title,
+            url: info.url,
+            artist: info.artist,
+            duration: info.duration,
+            live: info.stream_title.is_some(),
+        }
+    }
+}
+
+impl SavedTrack {
+    pub fn info(&self, requester: Option<UserId>) -> TrackInfo {
+        TrackInfo {
+            title: self.title.clone(),
+            url: self.url.clone(),
+            artist: self.artist.clone(),
+            duration: self.duration,
+            thumbnail: None,
+            requester,
+            stream_title: self.live.then(Default::default),
+        }
+    }
+}
+
+/// All of a guild's playlists by name, stored as one entry per guild.
+type Playlists = BTreeMap<String, Vec<SavedTrack>>;
+
+fn load_playlists(store: &Store, guild_id: GuildId) -> Playlists {
+    store.get(TABLE, &guild_id.to_string()).unwrap_or_default()
+}
+
+async fn update_playlists<T>(
+    ctx: &Context,
+    guild_id: GuildId,
+    f: impl FnOnce(&mut Playlists) -> Result<T, String>,
+) -> Result<T, String> {
+    let lock = guild_lock(ctx, guild_id).await;
+    let _guard = lock.lock().await;
+
+    let store = get_store(ctx).await;
+    let mut playlists = load_playlists(&store, guild_id);
+
+    let res = f(&mut playlists)?;
+
+    if let Err(e) = store.put(TABLE, &guild_id.to_string(), &playlists) {
+        error!("Failed to save playlists: {:?}", e);
+        return Err("failed to save playlists".to_string());
+    }
+
+    Ok(res)
+}
+
+fn usage() -> &'static str {
+    "Usage: `~playlist create|add|list|delete|load <name>`\n\
+     `~playlist add <name> [url]` adds the given URL, or the current track"
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn playlist(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let action = args.single::<String>().unwrap_or_default().to_lowercase();
+    let name = args.single::<String>().unwrap_or_default().to_lowercase();
+    let rest = args.rest().trim().to_string();
+
+    info!("Playlist: {} {}", action, name);
+
+    if name.is_empty() && action != "list" {
+        let _ = msg.channel_id.say(&ctx.http, usage()).await;
+        return Ok(());
+    }
+
+    let reply = match action.as_str() {
+        "create" => update_playlists(ctx, guild_id, |playlists| {
+            if playlists.contains_key(&name) {
+                return Err(format!("**{name}** already exists"));
+            }
+            playlists.insert(name.clone(), Vec::new());
+            Ok(format!("Created playlist **{name}**"))
+        })
+        .await
+        .unwrap_or_else(|e| format!("Couldn't create playlist: {e}")),
+        "add" => add(ctx, msg, guild_id, &name, &rest).await,
+        "list" => {
+            list(ctx, msg, guild_id, &name).await;
+            return Ok(());
+        }
+        "delete" => update_playlists(ctx, guild_id, |playlists| {
+            playlists
+                .remove(&name)
+                .map(|_| format!("Deleted playlist **{name}**"))
+                .ok_or_else(|| format!("there's no playlist called **{name}**"))
+        })
+        .await
+        .unwrap_or_else(|e| format!("Couldn't delete playlist: {e}")),
+        "load" => match load(ctx, msg, guild_id, &name).await {
+            Some(reply) => reply,
+            // Not in the caller's channel, which `caller_call` already replied to
+            None => return Ok(()),
+        },
+        _ => usage().to_string(),
+    };
+
+    let _ = msg.channel_id.say(&ctx.http, reply).await;
+
+    Ok(())
+}
+
+/// Adds a URL, or whatever is playing if there isn't one, to a playlist.
+async fn add(ctx: &Context, msg: &Message, guild_id: GuildId, name: &str, url: &str) -> String {
+    let info = if url.is_empty() {
+        let manager = songbird::get(ctx).await.unwrap().clone();
+        let current = match manager.get(guild_id) {
+            Some(call) => call.lock().await.queue().current(),
+            None => None,
+        };
+
+        match current {
+            Some(track) => get_track_info(&track).await,
+            None => return "Nothing is playing.".to_string(),
+        }
+    } else if url.starts_with("https://") {
+        let mut youtube_dl = YoutubeDl::new(get_http_client(ctx).await, url.to_string());
+        match TrackInfo::try_fetch(&mut youtube_dl, url, Some(msg.author.id)).await {
+            Ok(info) => Some(info),
+            Err(e) => {
+                error!("Failed to get metadata for {}: {:?}", url, e);
+                return format!("Couldn't find <{url}>");
+            }
+        }
+    } else {
+        return "Only `https://` URLs can be added directly.".to_string();
+    };
+
+    let Some(info) = info else {
+        return "Can't save the current track.".to_string();
+    };
+    let title = info.title.clone();
+
+    update_playlists(ctx, guild_id, |playlists| {
+        let tracks = playlists
+            .get_mut(name)
+            .ok_or_else(|| format!("there's no playlist called **{name}**"))?;
+        tracks.push(info.into());
+        Ok(format!(
+            "Added **{title}** to **{name}** ({} tracks)",
+            tracks.len()
+        ))
+    })
+    .await
+    .unwrap_or_else(|e| format!("Couldn't add to playlist: {e}"))
+}
+
+/// Lists the guild's playlists, or the tracks in one of them.
+async fn list(ctx: &Context, msg: &Message, guild_id: GuildId, name: &str) {
+    let playlists = load_playlists(&*get_store(ctx).await, guild_id);
+
+    let embed = if name.is_empty() {
+        if playlists.is_empty() {
+            let _ = msg
+                .channel_id
+                .say(
+                    &ctx.http,
+                    "No saved playlists. Make one with `~playlist create <name>`",
+                )
+                .await;
+            return;
+        }
+
+        let lines: Vec<String> = playlists
+            .iter()
+            .map(|(name, tracks)| format!("**{name}** ({} tracks)", tracks.len()))
+            .collect();
+        CreateEmbed::new()
+            .title("Playlists")
+            .description(lines.join("\n"))
+    } else {
+        let Some(tracks) = playlists.get(name) else {
+            let _ = msg
+                .channel_id
+                .say(&ctx.http, format!("There's no playlist called **{name}**"))
+                .await;
+            return;
+        };
+
+        let mut lines: Vec<String> = tracks
+            .iter()
+            .take(LIST_LIMIT)
+            .enumerate()
+            .map(|(i, track)| format!("**{}.** {}", i + 1, track.info(None).line()))
+            .collect();
+        if tracks.len() > LIST_LIMIT {
+            lines.push(format!("...and {} more", tracks.len() - LIST_LIMIT));
+        }
+
+        let total: Duration = tracks.iter().filter_map(|track| track.duration).sum();
+        CreateEmbed::new()
+            .title(name)
+            .description(lines.join("\n"))
+            .footer(CreateEmbedFooter::new(format!(
+                "{} tracks, {} total",
+                tracks.len(),
+                format_duration(total)
+            )))
+    };
+
+    let _ = msg
+        .channel_id
+        .send_message(&ctx.http, CreateMessage::new().embed(embed))
+        .await;
+}
+
+/// Queues every track in a playlist. Returns `None` if the caller isn't in the
+/// bot's voice channel.
+async fn load(ctx: &Context, msg: &Message, guild_id: GuildId, name: &str) -> Option<String> {
+    let call = caller_call(ctx, msg).await?;
+
+    let playlists = load_playlists(&*get_store(ctx).await, guild_id);
+    let Some(tracks) = playlists.get(name) else {
+        return Some(format!("There's no playlist called **{name}**"));
+    };
+
+    let mut handler = call.lock().await;
+    for track in tracks {
+        let info = track.info(Some(msg.author.id));
+        let input = info.input(ctx).await;
+        enqueue(ctx, guild_id, &mut handler, input, info).await;
+    }
+
+    Some(format!("Loaded {} tracks from **{name}**", tracks.len()))
+}
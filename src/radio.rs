@@ -0,0 +1,553 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult, SeekFrom};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Error;
+use log::{error, info, warn};
+use reqwest::header::{HeaderMap, CONTENT_TYPE, LOCATION};
+use reqwest::redirect::Policy;
+use reqwest::{Client as HttpClient, Response, Url};
+use serenity::async_trait;
+use songbird::input::core::io::MediaSource;
+use songbird::input::core::probe::Hint;
+use songbird::input::{
+    AsyncAdapterStream, AsyncMediaSource, AudioStream, AudioStreamError, Compose, Input,
+};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::net::lookup_host;
+use tokio::sync::mpsc;
+
+/// The song a station is currently playing, from its ICY metadata.
+pub type StreamTitle = Arc<Mutex<Option<String>>>;
+
+/// Give up on a station after this many reconnects in a row fail.
+const MAX_RECONNECTS: u32 = 8;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Sites yt-dlp knows how to play, which are never radio streams and so
+/// aren't worth probing.
+const YT_DLP_HOSTS: &[&str] = &[
+    "youtube.com",
+    "youtu.be",
+    "soundcloud.com",
+    "bandcamp.com",
+    "vimeo.com",
+    "twitch.tv",
+    "mixcloud.com",
+    "dailymotion.com",
+    "twitter.com",
+    "x.com",
+    "tiktok.com",
+];
+/// Redirects are followed by hand, so each hop can be checked.
+const MAX_REDIRECTS: usize = 5;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Station {
+    pub name: Option<String>,
+    /// Where the stream ended up after redirects
+    pub url: String,
+}
+
+fn is_yt_dlp_host(host: &str) -> bool {
+    let host = host.trim_start_matches("www.");
+    YT_DLP_HOSTS
+        .iter()
+        .any(|known| host == *known || host.ends_with(&format!(".{known}")))
+}
+
+/// Whether an address is on the public internet, rather than loopback, a
+/// private network or the like.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // "This network", including 0.0.0.0
+                || a == 0
+                // Carrier-grade NAT
+                || (a == 100 && (b & 0xc0) == 64)
+                // Benchmarking
+                || (a == 198 && (b & 0xfe) == 18)
+                // Reserved, including broadcast
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            let first = segments[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local and link local
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // NAT64, which can reach private IPv4 addresses
+                || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+                // Documentation
+                || segments[..2] == [0x2001, 0xdb8])
+        }
+    }
+}
+
+/// The addresses the URL points at, if every one of them is public, so users
+/// can't get the bot to fetch things from the network it runs on.
+async fn public_addrs(url: &Url) -> Option<Vec<SocketAddr>> {
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host = url.host_str()?;
+
+    let addrs: Vec<SocketAddr> = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => lookup_host((host, port)).await.ok()?.collect(),
+    };
+
+    (!addrs.is_empty() && addrs.iter().all(|addr| is_public(addr.ip()))).then_some(addrs)
+}
+
+/// Requests a stream, following redirects by hand so every hop is checked to
+/// be public. Each request only connects to the addresses that were checked,
+/// so DNS can't change between the check and the connection.
+async fn fetch(mut url: Url) -> Result<(Url, Response), Error> {
+    for _ in 0..=MAX_REDIRECTS {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(Error::msg(format!("{url} is not http")));
+        }
+        let host = url.host_str().unwrap_or_default().to_string();
+        let addrs = public_addrs(&url)
+            .await
+            .ok_or_else(|| Error::msg(format!("{url} is not on a public address")))?;
+
+        let client = HttpClient::builder()
+            .redirect(Policy::none())
+            .connect_timeout(CONNECT_TIMEOUT)
+            .resolve_to_addrs(&host, &addrs)
+            .build()?;
+        let resp = client
+            .get(url.clone())
+            .header("Icy-MetaData", "1")
+            .send()
+            .await?;
+
+        if resp.status().is_redirection() {
+            let location = header(resp.headers(), LOCATION.as_str())
+                .ok_or_else(|| Error::msg("redirect without a location"))?;
+            url = url.join(&location)?;
+            continue;
+        }
+
+        return Ok((url, resp.error_for_status()?));
+    }
+
+    Err(Error::msg("too many redirects"))
+}
+
+/// Checks whether a URL is a live audio stream (Icecast, Shoutcast or a plain
+/// endless mp3/aac stream) that should be played directly instead of going
+/// through yt-dlp. Sites yt-dlp handles and non-public addresses are never
+/// fetched.
+pub async fn probe(url: &str) -> Option<Station> {
+    let url = Url::parse(url).ok()?;
+    if url.host_str().is_some_and(is_yt_dlp_host) {
+        return None;
+    }
+
+    let (url, resp) = match tokio::time::timeout(PROBE_TIMEOUT, fetch(url.clone())).await {
+        Ok(Ok(fetched)) => fetched,
+        Ok(Err(e)) => {
+            warn!("Not probing {}: {:?}", url, e);
+            return None;
+        }
+        Err(_) => return None,
+    };
+
+    let headers = resp.headers();
+    let is_icy = headers.keys().any(|key| key.as_str().starts_with("icy-"));
+    let is_audio = header(headers, CONTENT_TYPE.as_str())
+        .is_some_and(|mime| mime.starts_with("audio/") || mime == "application/ogg");
+    // Files have a length, live streams don't
+    let is_endless = resp.content_length().is_none();
+
+    if !(is_icy || (is_audio && is_endless)) {
+        return None;
+    }
+
+    Some(Station {
+        name: header(headers, "icy-name"),
+        url: url.to_string(),
+    })
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// A lazily started radio stream. Unlike `HttpRequest`, it strips the ICY
+/// metadata out of the audio and reconnects when the stream drops.
+#[derive(Clone)]
+pub struct Radio {
+    url: String,
+    title: StreamTitle,
+}
+
+impl Radio {
+    pub fn new(url: String, title: StreamTitle) -> Self {
+        Self { url, title }
+    }
+
+    /// Connects with the same checks as `probe`, every time, since the
+    /// station's DNS or redirects can change under us.
+    async fn connect(&self) -> Result<Response, Error> {
+        let (_, resp) = fetch(Url::parse(&self.url)?).await?;
+        Ok(resp)
+    }
+
+    /// Pumps audio from the station into `tx` until the track is dropped,
+    /// reconnecting with backoff whenever the connection is lost.
+    async fn run(self, mut resp: Response, tx: mpsc::Sender<Vec<u8>>) {
+        let mut failures = 0;
+
+        loop {
+            let metaint = header(resp.headers(), "icy-metaint")
+                .and_then(|n| n.parse().ok())
+                .filter(|n| *n > 0);
+            let mut reader = IcyReader::new(metaint);
+
+            loop {
+                match resp.chunk().await {
+                    Ok(Some(chunk)) => {
+                        failures = 0;
+
+                        let audio = reader.feed(&chunk, &self.title);
+                        if !audio.is_empty() && tx.send(audio).await.is_err() {
+                            // The track was stopped
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Radio stream {} failed: {:?}", self.url, e);
+                        break;
+                    }
+                }
+            }
+
+            loop {
+                if tx.is_closed() {
+                    return;
+                }
+
+                failures += 1;
+                if failures > MAX_RECONNECTS {
+                    error!("Giving up on radio stream {}", self.url);
+                    return;
+                }
+
+                let delay = RECONNECT_DELAY * 2u32.pow(failures.min(5) - 1);
+                info!("Reconnecting to {} in {:?}", self.url, delay);
+                tokio::time::sleep(delay).await;
+
+                match self.connect().await {
+                    Ok(new_resp) => {
+                        resp = new_resp;
+                        break;
+                    }
+                    Err(e) => warn!("Couldn't reconnect to {}: {:?}", self.url, e),
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Compose for Radio {
+    fn create(&mut self) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+        Err(AudioStreamError::Unsupported)
+    }
+
+    async fn create_async(
+        &mut self,
+    ) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+        let resp = self
+            .connect()
+            .await
+            .map_err(|e| AudioStreamError::Fail(e.into()))?;
+
+        let hint = header(resp.headers(), CONTENT_TYPE.as_str()).map(|mime| {
+            let mut hint = Hint::new();
+            hint.mime_type(&mime);
+            hint
+        });
+
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(self.clone().run(resp, tx));
+
+        let stream = RadioStream {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+        };
+
+        Ok(AudioStream {
+            input: Box::new(AsyncAdapterStream::new(Box::new(stream), 64 * 1024)),
+            hint,
+        })
+    }
+
+    fn should_create_async(&self) -> bool {
+        true
+    }
+}
+
+impl From<Radio> for Input {
+    fn from(radio: Radio) -> Self {
+        Input::Lazy(Box::new(radio))
+    }
+}
+
+/// Splits ICY metadata blocks, which the server inserts every `metaint` bytes,
+/// out of the audio.
+struct IcyReader {
+    metaint: Option<usize>,
+    until_meta: usize,
+    /// Metadata bytes still to read, or `None` while reading audio
+    meta_left: Option<usize>,
+    meta: Vec<u8>,
+}
+
+impl IcyReader {
+    fn new(metaint: Option<usize>) -> Self {
+        Self {
+            metaint,
+            until_meta: metaint.unwrap_or_default(),
+            meta_left: None,
+            meta: Vec::new(),
+        }
+    }
+
+    /// Returns the audio in `data`, updating `title` from any metadata in it.
+    fn feed(&mut self, mut data: &[u8], title: &StreamTitle) -> Vec<u8> {
+        let Some(metaint) = self.metaint else {
+            return data.to_vec();
+        };
+
+        let mut audio = Vec::with_capacity(data.len());
+
+        while !data.is_empty() {
+            match self.meta_left {
+                None if self.until_meta > 0 => {
+                    let n = self.until_meta.min(data.len());
+                    audio.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                    self.until_meta -= n;
+                }
+                // The first byte of a block is its length in 16 byte units
+                None => {
+                    self.meta_left = Some(data[0] as usize * 16);
+                    data = &data[1..];
+                }
+                Some(left) => {
+                    let n = left.min(data.len());
+                    self.meta.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                    self.meta_left = Some(left - n);
+                }
+            }
+
+            if self.meta_left == Some(0) {
+                if let Some(stream_title) = parse_stream_title(&self.meta) {
+                    if let Ok(mut title) = title.lock() {
+                        *title = Some(stream_title);
+                    }
+                }
+
+                self.meta.clear();
+                self.meta_left = None;
+                self.until_meta = metaint;
+            }
+        }
+
+        audio
+    }
+}
+
+/// Gets the title out of a metadata block like
+/// `StreamTitle='Artist - Song';StreamUrl='';`
+fn parse_stream_title(meta: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(meta);
+    let start = text.find("StreamTitle='")? + "StreamTitle='".len();
+    let end = text[start..].find("';").map(|end| start + end)?;
+
+    Some(text[start..end].trim().to_string()).filter(|title| !title.is_empty())
+}
+
+/// The audio side of a `Radio`, fed by its connection task.
+struct RadioStream {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncRead for RadioStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let stream = &mut *self;
+
+        if stream.pos >= stream.chunk.len() {
+            match stream.rx.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    stream.chunk = chunk;
+                    stream.pos = 0;
+                }
+                // The station is gone for good, so end the track
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = buf.remaining().min(stream.chunk.len() - stream.pos);
+        buf.put_slice(&stream.chunk[stream.pos..stream.pos + n]);
+        stream.pos += n;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for RadioStream {
+    fn start_seek(self: Pin<&mut Self>, _position: SeekFrom) -> IoResult<()> {
+        Err(IoError::new(
+            IoErrorKind::Unsupported,
+            "radio streams can't seek",
+        ))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<IoResult<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+#[async_trait]
+impl AsyncMediaSource for RadioStream {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    async fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `abcd`, a 16 byte metadata block, then `efgh`, with a metaint of 4.
+    fn stream() -> Vec<u8> {
+        let mut stream = b"abcd".to_vec();
+        stream.push(1);
+        stream.extend_from_slice(b"StreamTitle='X';");
+        stream.extend_from_slice(b"efgh");
+        stream
+    }
+
+    #[test]
+    fn strips_metadata_and_updates_the_title() {
+        let title = StreamTitle::default();
+        let mut reader = IcyReader::new(Some(4));
+
+        assert_eq!(reader.feed(&stream(), &title), b"abcdefgh");
+        assert_eq!(title.lock().unwrap().as_deref(), Some("X"));
+    }
+
+    #[test]
+    fn handles_metadata_split_across_chunks() {
+        let title = StreamTitle::default();
+        let mut reader = IcyReader::new(Some(4));
+
+        let audio: Vec<u8> = stream()
+            .chunks(3)
+            .flat_map(|chunk| reader.feed(chunk, &title))
+            .collect();
+        assert_eq!(audio, b"abcdefgh");
+        assert_eq!(title.lock().unwrap().as_deref(), Some("X"));
+    }
+
+    #[test]
+    fn skips_empty_metadata_blocks() {
+        let title = StreamTitle::default();
+        let mut reader = IcyReader::new(Some(2));
+
+        assert_eq!(reader.feed(b"ab\0cd\0ef", &title), b"abcdef");
+        assert_eq!(*title.lock().unwrap(), None);
+    }
+
+    #[test]
+    fn passes_audio_through_without_metaint() {
+        let title = StreamTitle::default();
+        let mut reader = IcyReader::new(None);
+
+        assert_eq!(reader.feed(&stream(), &title), stream());
+    }
+
+    #[test]
+    fn parses_stream_titles() {
+        assert_eq!(
+            parse_stream_title(b"StreamTitle='Artist - Song';StreamUrl='';").as_deref(),
+            Some("Artist - Song")
+        );
+        assert_eq!(parse_stream_title(b"StreamTitle='';"), None);
+        assert_eq!(parse_stream_title(b"StreamUrl='x';"), None);
+    }
+
+    #[test]
+    fn only_public_addresses_are_allowed() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "198.18.0.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::1",
+            "fd00::1",
+            "ff02::1",
+            "::ffff:10.0.0.1",
+            "64:ff9b::a00:1",
+            "2001:db8::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["1.1.1.1", "198.20.0.1", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn knows_yt_dlp_hosts() {
+        assert!(is_yt_dlp_host("www.youtube.com"));
+        assert!(is_yt_dlp_host("music.youtube.com"));
+        assert!(!is_yt_dlp_host("notyoutube.com"));
+        assert!(!is_yt_dlp_host("stream.example.com"));
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serenity::all::GuildId;
+use serenity::builder::{CreateEmbed, CreateEmbedFooter, CreateMessage};
+use serenity::client::Context;
+use serenity::framework::standard::macros::command;
+use serenity::framework::standard::{Args, CommandResult};
+use serenity::model::channel::Message;
+
+use crate::music::format_duration;
+use crate::session::{get_sessions, RECORDINGS_DIR};
+use crate::settings::guild_settings;
+use crate::store::{get_store, guild_lock};
+
+const TABLE: &str = "pins";
+
+/// How often old recordings are cleaned up.
+pub const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A session's recordings on disk.
+struct Recording {
+    guild_id: GuildId,
+    /// The session's start time in milliseconds, which is its directory name
+    id: String,
+    started: DateTime<Utc>,
+    path: PathBuf,
+    bytes: u64,
+}
+
+/// Total size the recordings may take up, from `RECORDINGS_QUOTA_MB`.
+fn quota() -> Option<u64> {
+    env::var("RECORDINGS_QUOTA_MB")
+        .ok()
+        .and_then(|mb| mb.parse::<u64>().ok())
+        .filter(|mb| *mb > 0)
+        .map(|mb| mb * 1024 * 1024)
+}
+
+/// Warn before recording when less than this is free, from `MIN_FREE_DISK_MB`.
+fn min_free() -> u64 {
+    env::var("MIN_FREE_DISK_MB")
+        .ok()
+        .and_then(|mb| mb.parse::<u64>().ok())
+        .unwrap_or(1024)
+        * 1024
+        * 1024
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Every session directory under `cache/<guild id>/<start time>`, oldest
+/// first.
+fn recordings() -> Vec<Recording> {
+    let mut recordings = Vec::new();
+
+    let Ok(guilds) = fs::read_dir(RECORDINGS_DIR) else {
+        return recordings;
+    };
+    for guild in guilds.flatten() {
+        let Some(guild_id) = guild
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u64>().ok())
+            .filter(|id| *id != 0)
+        else {
+            continue;
+        };
+        let Ok(sessions) = fs::read_dir(guild.path()) else {
+            continue;
+        };
+
+        for session in sessions.flatten() {
+            let id = session.file_name().to_string_lossy().into_owned();
+            let Some(started) = id
+                .parse::<i64>()
+                .ok()
+                .and_then(DateTime::from_timestamp_millis)
+            else {
+                continue;
+            };
+
+            recordings.push(Recording {
+                guild_id: GuildId::new(guild_id),
+                id,
+                started,
+                bytes: dir_size(&session.path()),
+                path: session.path(),
+            });
+        }
+    }
+
+    recordings.sort_by_key(|recording| recording.started);
+    recordings
+}
+
+async fn pins(ctx: &Context, guild_id: GuildId) -> BTreeSet<String> {
+    get_store(ctx)
+        .await
+        .get(TABLE, &guild_id.to_string())
+        .unwrap_or_default()
+}
+
+/// Whether a recording may be deleted: not pinned and not still being made.
+async fn is_removable(ctx: &Context, recording: &Recording) -> bool {
+    let active = get_sessions(ctx)
+        .await
+        .get(&recording.guild_id)
+        .is_some_and(|session| session.dir == recording.path);
+
+    !active && !pins(ctx, recording.guild_id).await.contains(&recording.id)
+}
+
+/// Deletes a recording if it's allowed to go, holding the guild lock so it
+/// can't be pinned in the meantime. Returns whether it was deleted.
+async fn remove_if_allowed(ctx: &Context, recording: &Recording, why: &str) -> bool {
+    let lock = guild_lock(ctx, recording.guild_id).await;
+    let _guard = lock.lock().await;
+
+    if !is_removable(ctx, recording).await {
+        return false;
+    }
+    remove(recording, why);
+    true
+}
+
+fn remove(recording: &Recording, why: &str) {
+    info!(
+        "Deleting recording {} ({}, {} KB)",
+        recording.path.display(),
+        why,
+        recording.bytes / 1024
+    );
+    if let Err(e) = fs::remove_dir_all(&recording.path) {
+        error!("Failed to delete {}: {}", recording.path.display(), e);
+    }
+}
+
+/// Deletes recordings older than their guild keeps them for, then the oldest
+/// ones until everything fits in the quota. Pinned and in-progress sessions
+/// are never touched.
+pub async fn enforce(ctx: &Context) {
+    let mut kept = Vec::new();
+
+    for recording in recordings() {
+        let retention_days = guild_settings(ctx, recording.guild_id).await.retention_days;
+        let expired = retention_days > 0
+            && Utc::now() - recording.started > chrono::Duration::days(retention_days.into());
+
+        if !(expired && remove_if_allowed(ctx, &recording, "expired").await) {
+            kept.push(recording);
+        }
+    }
+
+    let Some(quota) = quota() else {
+        return;
+    };
+
+    let mut total: u64 = kept.iter().map(|recording| recording.bytes).sum();
+    for recording in &kept {
+        if total <= quota {
+            break;
+        }
+        if remove_if_allowed(ctx, recording, "over quota").await {
+            total -= recording.bytes;
+        }
+    }
+
+    if total > quota {
+        warn!(
+            "Recordings take up {} MB, over the {} MB quota, but the rest are pinned or in use",
+            total / 1024 / 1024,
+            quota / 1024 / 1024
+        );
+    }
+}
+
+/// Space left on the disk the recordings are saved to.
+#[cfg(unix)]
+pub fn free_space() -> Option<u64> {
+    let _ = fs::create_dir_all(RECORDINGS_DIR);
+    let path = std::ffi::CString::new(RECORDINGS_DIR).ok()?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid C string and `stat` is a valid buffer for
+    // statvfs to fill in
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Free space isn't checked on other platforms.
+#[cfg(not(unix))]
+pub fn free_space() -> Option<u64> {
+    None
+}
+
+/// A warning to post before recording if the disk is nearly full.
+pub async fn low_space_warning(ctx: &Context) -> Option<String> {
+    if free_space()? >= min_free() {
+        return None;
+    }
+
+    // Make as much room as the policy allows before complaining
+    enforce(ctx).await;
+
+    let free = free_space()?;
+    (free < min_free()).then(|| {
+        warn!("Low disk space: {} MB free", free / 1024 / 1024);
+        format!(
+            "⚠️ Only {} MB of disk space left, so recordings may get cut short.",
+            free / 1024 / 1024
+        )
+    })
+}
+
+async fn set_pinned(ctx: &Context, msg: &Message, mut args: Args, pinned: bool) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let recordings: Vec<Recording> = recordings()
+        .into_iter()
+        .filter(|recording| recording.guild_id == guild_id)
+        .collect();
+
+    // Defaults to the latest session
+    let id = match args.single::<String>() {
+        Ok(id) => id,
+        Err(_) => match recordings.last() {
+            Some(recording) => recording.id.clone(),
+            None => {
+                let _ = msg
+                    .channel_id
+                    .say(&ctx.http, "There are no recordings.")
+                    .await;
+                return Ok(());
+            }
+        },
+    };
+
+    if !recordings.iter().any(|recording| recording.id == id) {
+        let _ = msg
+            .channel_id
+            .say(
+                &ctx.http,
+                format!("No recording `{id}`; see `~recordings`."),
+            )
+            .await;
+        return Ok(());
+    }
+
+    let lock = guild_lock(ctx, guild_id).await;
+    let guard = lock.lock().await;
+
+    let mut pins = pins(ctx, guild_id).await;
+    if pinned {
+        pins.insert(id.clone());
+    } else {
+        pins.remove(&id);
+    }
+    get_store(ctx)
+        .await
+        .put(TABLE, &guild_id.to_string(), &pins)?;
+    drop(guard);
+
+    let reply = if pinned {
+        format!("Pinned recording `{id}`; it'll be kept until unpinned.")
+    } else {
+        format!("Unpinned recording `{id}`.")
+    };
+    let _ = msg.channel_id.say(&ctx.http, reply).await;
+
+    Ok(())
+}
+
+/// How long ago a recording was made, in days once it's that old.
+fn format_age(age: Duration) -> String {
+    let hours = age.as_secs() / 3600;
+    match hours / 24 {
+        0 => format_duration(age),
+        1 => format!("1 day {}h", hours % 24),
+        days => format!("{days} days {}h", hours % 24),
+    }
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(MANAGE_GUILD)]
+pub async fn pin(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    set_pinned(ctx, msg, args, true).await
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(MANAGE_GUILD)]
+pub async fn unpin(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    set_pinned(ctx, msg, args, false).await
+}
+
+#[command("recordings")]
+#[only_in(guilds)]
+pub async fn recordings_list(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let pins = pins(ctx, guild_id).await;
+    let retention_days = guild_settings(ctx, guild_id).await.retention_days;
+
+    let lines: Vec<String> = recordings()
+        .into_iter()
+        .filter(|recording| recording.guild_id == guild_id)
+        .rev()
+        .take(20)
+        .map(|recording| {
+            let age = (Utc::now() - recording.started)
+                .to_std()
+                .unwrap_or_default();
+            format!(
+                "{}`{}` {}, {} MB, {} ago",
+                if pins.contains(&recording.id) {
+                    "📌 "
+                } else {
+                    ""
+                },
+                recording.id,
+                recording.started.format("%Y-%m-%d %H:%M UTC"),
+                recording.bytes / 1024 / 1024,
+                format_age(age),
+            )
+        })
+        .collect();
+
+    if lines.is_empty() {
+        let _ = msg
+            .channel_id
+            .say(&ctx.http, "There are no recordings.")
+            .await;
+        return Ok(());
+    }
+
+    let kept = match retention_days {
+        0 => "Recordings are kept until the disk quota runs out".to_string(),
+        days => format!("Unpinned recordings are deleted after {days} days"),
+    };
+    let embed = CreateEmbed::new()
+        .title("Recordings")
+        .description(lines.join("\n"))
+        .footer(CreateEmbedFooter::new(kept));
+
+    let _ = msg
+        .channel_id
+        .send_message(&ctx.http, CreateMessage::new().embed(embed))
+        .await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_ages_in_days() {
+        let hours = |n: u64| Duration::from_secs(n * 3600);
+
+        assert_eq!(format_age(hours(5)), "5:00:00");
+        assert_eq!(format_age(hours(30)), "1 day 6h");
+        assert_eq!(format_age(hours(24 * 12 + 1)), "12 days 1h");
+    }
+}
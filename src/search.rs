@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Error;
+use log::{error, info, warn};
+use reqwest::Client as HttpClient;
+use serde::Deserialize;
+use serenity::async_trait;
+use tokio::process::Command;
+
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub title: String,
+    pub channel: String,
+    pub duration: Option<Duration>,
+    pub url: String,
+}
+
+#[async_trait]
+pub trait SearchProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn search(&self, query: &str, max_results: usize) -> Result<Vec<SearchResult>, Error>;
+}
+
+/// Picks search providers from what's configured:
+/// - `SEARCH_INDEX`: only search a static JSON index (for tests / offline use)
+/// - `YOUTUBE_API_KEY`: the YouTube Data API, falling back to yt-dlp if it fails
+/// - otherwise: yt-dlp's own search
+pub fn provider_from_env(client: HttpClient) -> Arc<dyn SearchProvider> {
+    if let Ok(path) = env::var("SEARCH_INDEX") {
+        match LocalIndex::load(&path) {
+            Ok(index) => {
+                info!("Searching local index {}", path);
+                return Arc::new(index);
+            }
+            Err(e) => error!("Failed to load search index [{path}]: {:?}", e),
+        }
+    }
+
+    match env::var("YOUTUBE_API_KEY") {
+        Ok(api_key) if !api_key.is_empty() => Arc::new(Fallback(vec![
+            Box::new(YoutubeApi { client, api_key }),
+            Box::new(YtDlp),
+        ])),
+        _ => {
+            info!("YOUTUBE_API_KEY not set, searching with yt-dlp");
+            Arc::new(YtDlp)
+        }
+    }
+}
+
+/// Tries each provider in turn until one of them works.
+struct Fallback(Vec<Box<dyn SearchProvider>>);
+
+#[async_trait]
+impl SearchProvider for Fallback {
+    fn name(&self) -> &'static str {
+        "fallback"
+    }
+
+    async fn search(&self, query: &str, max_results: usize) -> Result<Vec<SearchResult>, Error> {
+        let mut last_error = Error::msg("no search providers configured");
+
+        for provider in &self.0 {
+            match provider.search(query, max_results).await {
+                Ok(results) => return Ok(results),
+                Err(e) => {
+                    warn!("{} search failed: {:?}", provider.name(), e);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+/// The YouTube Data API.
+struct YoutubeApi {
+    client: HttpClient,
+    api_key: String,
+}
+
+#[async_trait]
+impl SearchProvider for YoutubeApi {
+    fn name(&self) -> &'static str {
+        "youtube"
+    }
+
+    async fn search(&self, query: &str, max_results: usize) -> Result<Vec<SearchResult>, Error> {
+        let search_results = self
+            .client
+            .get("https://www.googleapis.com/youtube/v3/search")
+            .query(&[
+                ("key", self.api_key.as_str()),
+                ("part", "snippet"),
+                ("type", "video"),
+                ("maxResults", &max_results.to_string()),
+                ("q", query),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<serde_json::Value>()
+            .await?;
+
+        let items = search_results["items"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let ids: Vec<&str> = items
+            .iter()
+            .filter_map(|item| item["id"]["videoId"].as_str())
+            .collect();
+
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Search results don't include durations, so look them up in one request
+        let details = self
+            .client
+            .get("https://www.googleapis.com/youtube/v3/videos")
+            .query(&[
+                ("key", self.api_key.as_str()),
+                ("part", "contentDetails"),
+                ("id", &ids.join(",")),
+            ])
+            .send()
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
+
+        let durations: HashMap<&str, Duration> = details["items"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|item| {
+                let id = item["id"].as_str()?;
+                let duration = parse_iso_duration(item["contentDetails"]["duration"].as_str()?)?;
+                Some((id, duration))
+            })
+            .collect();
+
+        Ok(items
+            .iter()
+            .filter_map(|item| {
+                let id = item["id"]["videoId"].as_str()?;
+                Some(SearchResult {
+                    title: item["snippet"]["title"].as_str()?.to_string(),
+                    channel: item["snippet"]["channelTitle"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    duration: durations.get(id).copied(),
+                    url: format!("https://www.youtube.com/watch?v={}", id),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Parses YouTube's ISO 8601 durations, e.g. `PT1H2M3S`.
+fn parse_iso_duration(text: &str) -> Option<Duration> {
+    let mut secs = 0;
+    let mut num = 0;
+
+    for c in text.strip_prefix("PT")?.chars() {
+        match c {
+            '0'..='9' => num = num * 10 + c.to_digit(10)? as u64,
+            'H' => secs += std::mem::take(&mut num) * 3600,
+            'M' => secs += std::mem::take(&mut num) * 60,
+            'S' => secs += std::mem::take(&mut num),
+            _ => return None,
+        }
+    }
+
+    Some(Duration::from_secs(secs))
+}
+
+/// yt-dlp's `ytsearch:`, which needs no API key.
+struct YtDlp;
+
+#[async_trait]
+impl SearchProvider for YtDlp {
+    fn name(&self) -> &'static str {
+        "yt-dlp"
+    }
+
+    async fn search(&self, query: &str, max_results: usize) -> Result<Vec<SearchResult>, Error> {
+        run_yt_dlp(&[
+            "-j",
+            "--flat-playlist",
+            &format!("ytsearch{max_results}:{query}"),
+        ])
+        .await
+    }
+}
+
+/// Whether a URL points at a whole playlist rather than a single video. Video
+/// links that happen to be played from a playlist (`watch?v=...&list=...`)
+/// count as the single video.
+pub fn is_playlist(url: &str) -> bool {
+    url.contains("/playlist?") || (url.contains("list=") && !url.contains("v="))
+}
+
+/// Lists the first `limit` entries of a playlist without resolving each of
+/// them, so they can be queued as lazy tracks.
+pub async fn expand_playlist(url: &str, limit: usize) -> Result<Vec<SearchResult>, Error> {
+    run_yt_dlp(&[
+        "-j",
+        "--flat-playlist",
+        "--playlist-end",
+        &limit.to_string(),
+        url,
+    ])
+    .await
+}
+
+async fn run_yt_dlp(args: &[&str]) -> Result<Vec<SearchResult>, Error> {
+    let output = Command::new("yt-dlp").args(args).output().await?;
+
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "yt-dlp failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    // One JSON object per line, one line per entry
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter_map(|entry| {
+            let id = entry["id"].as_str()?;
+            // Flat entries from other sites carry their own URL
+            let url = match entry["url"].as_str() {
+                Some(url) if url.starts_with("http") => url.to_string(),
+                _ => format!("https://www.youtube.com/watch?v={}", id),
+            };
+
+            Some(SearchResult {
+                title: entry["title"].as_str().unwrap_or(&url).to_string(),
+                channel: entry["channel"]
+                    .as_str()
+                    .or(entry["uploader"].as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                duration: entry["duration"].as_f64().map(Duration::from_secs_f64),
+                url,
+            })
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    title: String,
+    #[serde(default)]
+    channel: String,
+    /// Seconds
+    duration: Option<u64>,
+    url: String,
+}
+
+/// A fixed list of songs loaded from a JSON file, for tests and offline use.
+struct LocalIndex(Vec<SearchResult>);
+
+impl LocalIndex {
+    fn load(path: &str) -> Result<Self, Error> {
+        let entries: Vec<IndexEntry> = serde_json::from_slice(&fs::read(path)?)?;
+
+        Ok(Self(
+            entries
+                .into_iter()
+                .map(|entry| SearchResult {
+                    title: entry.title,
+                    channel: entry.channel,
+                    duration: entry.duration.map(Duration::from_secs),
+                    url: entry.url,
+                })
+                .collect(),
+        ))
+    }
+}
+
+#[async_trait]
+impl SearchProvider for LocalIndex {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn search(&self, query: &str, max_results: usize) -> Result<Vec<SearchResult>, Error> {
+        let words: Vec<String> = query
+            .split_whitespace()
+            .map(|word| word.to_lowercase())
+            .collect();
+
+        // Rank by how many of the query's words appear in the title or channel
+        let mut matches: Vec<(usize, &SearchResult)> = self
+            .0
+            .iter()
+            .map(|result| {
+                let haystack = format!("{} {}", result.title, result.channel).to_lowercase();
+                let score = words.iter().filter(|word| haystack.contains(*word)).count();
+                (score, result)
+            })
+            .filter(|(score, _)| *score > 0)
+            .collect();
+        matches.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+
+        Ok(matches
+            .into_iter()
+            .take(max_results)
+            .map(|(_, result)| result.clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(title: &str, channel: &str) -> SearchResult {
+        SearchResult {
+            title: title.to_string(),
+            channel: channel.to_string(),
+            duration: None,
+            url: format!("https://example.com/{title}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn local_index_ranks_by_matching_words() {
+        let index = LocalIndex(vec![
+            result("Never Gonna Give You Up", "Rick Astley"),
+            result("Give It Away", "Red Hot Chili Peppers"),
+            result("Bohemian Rhapsody", "Queen"),
+        ]);
+
+        let titles = |results: Vec<SearchResult>| -> Vec<String> {
+            results.into_iter().map(|result| result.title).collect()
+        };
+
+        assert_eq!(
+            titles(index.search("give you up", 5).await.unwrap()),
+            vec!["Never Gonna Give You Up", "Give It Away"]
+        );
+        assert_eq!(
+            titles(index.search("QUEEN", 5).await.unwrap()),
+            vec!["Bohemian Rhapsody"]
+        );
+        assert_eq!(index.search("give", 1).await.unwrap().len(), 1);
+        assert!(index.search("nothing here", 5).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_iso_durations() {
+        assert_eq!(
+            parse_iso_duration("PT1H2M3S"),
+            Some(Duration::from_secs(3723))
+        );
+        assert_eq!(parse_iso_duration("PT4M"), Some(Duration::from_secs(240)));
+        assert_eq!(parse_iso_duration("PT45S"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_iso_duration("P1D"), None);
+        assert_eq!(parse_iso_duration("PT1X"), None);
+    }
+
+    #[test]
+    fn detects_playlists() {
+        assert!(is_playlist("https://www.youtube.com/playlist?list=PL1234"));
+        assert!(is_playlist("https://youtube.com/something?list=PL1234"));
+        assert!(!is_playlist(
+            "https://www.youtube.com/watch?v=abc&list=PL1234"
+        ));
+        assert!(!is_playlist("https://www.youtube.com/watch?v=abc"));
+    }
+}
@@ -0,0 +1,217 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use crypto_secretbox::Key;
+use dashmap::{DashMap, DashSet};
+use log::warn;
+use serenity::all::GuildId;
+use serenity::builder::{CreateEmbed, CreateMessage};
+use serenity::client::Context;
+use serenity::framework::standard::macros::command;
+use serenity::framework::standard::{Args, CommandResult};
+use serenity::model::channel::Message;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::music::format_duration;
+use crate::state::SessionKey;
+use crate::transcript::Transcript;
+use crate::voice::VoiceController;
+
+/// Recordings are kept under `<RECORDINGS_DIR>/<guild id>/<session start>`.
+pub const RECORDINGS_DIR: &str = "cache";
+
+/// A slice saved to disk and waiting to be transcribed.
+pub struct Transcription {
+    pub filename: String,
+    pub user_id: Option<u64>,
+    pub ssrc: u32,
+    pub timestamp: DateTime<Utc>,
+    pub bot_playback: bool,
+    /// Whether the text may contain a voice command
+    pub trigger: bool,
+}
+
+#[derive(Default)]
+pub struct SessionStats {
+    /// Slices saved to disk
+    pub slices: AtomicUsize,
+    /// Milliseconds of speech in those slices
+    pub speech_ms: AtomicU64,
+    /// Slices waiting to be transcribed
+    pub queued: AtomicUsize,
+    pub transcribed: AtomicUsize,
+    pub failed: AtomicUsize,
+    /// Slices thrown away for being silence or noise
+    pub dropped: AtomicUsize,
+}
+
+/// Everything belonging to one guild's time in voice, from joining until
+/// leaving. Each guild gets its own, so sessions running side by side never
+/// share recordings, speakers or transcripts.
+pub struct Session {
+    pub guild_id: GuildId,
+    pub started: DateTime<Utc>,
+    pub dir: PathBuf,
+    /// Recordings and transcripts are encrypted with this, if it's set
+    pub key: Option<Key>,
+    pub controller: Arc<VoiceController>,
+    pub transcript: Arc<Mutex<Transcript>>,
+    pub stats: SessionStats,
+    /// Users who have been recorded so far, for the consent audit log
+    pub recorded: DashSet<u64>,
+    /// Slices are transcribed one at a time, in the order they were spoken.
+    /// Dropped when the session ends, which lets the transcriber finish up.
+    transcriptions: Mutex<Option<mpsc::UnboundedSender<Transcription>>>,
+    /// The task working through `transcriptions`
+    transcriber: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Session {
+    /// Returns the session along with the receiving end of its transcription
+    /// queue, which whoever does the transcribing should drain.
+    pub fn new(
+        guild_id: GuildId,
+        key: Option<Key>,
+    ) -> (Self, mpsc::UnboundedReceiver<Transcription>) {
+        let started = Utc::now();
+        let dir = PathBuf::from(RECORDINGS_DIR)
+            .join(guild_id.to_string())
+            .join(started.timestamp_millis().to_string());
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let session = Self {
+            guild_id,
+            started,
+            dir,
+            key,
+            controller: Arc::new(VoiceController::new()),
+            transcript: Arc::new(Mutex::new(Transcript::new(guild_id))),
+            stats: SessionStats::default(),
+            recorded: DashSet::new(),
+            transcriptions: Mutex::new(Some(tx)),
+            transcriber: Mutex::new(None),
+        };
+
+        (session, rx)
+    }
+
+    pub fn queue_transcription(&self, transcription: Transcription) {
+        let Ok(transcriptions) = self.transcriptions.lock() else {
+            return;
+        };
+        if let Some(tx) = transcriptions.as_ref() {
+            if tx.send(transcription).is_ok() {
+                self.stats.queued.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn set_transcriber(&self, transcriber: JoinHandle<()>) {
+        if let Ok(mut slot) = self.transcriber.lock() {
+            *slot = Some(transcriber);
+        }
+    }
+
+    /// Stops taking new slices and waits up to `limit` for the ones already
+    /// queued to be transcribed, so the transcript is complete before it's
+    /// saved.
+    pub async fn finish_transcriptions(&self, limit: Duration) {
+        if let Ok(mut transcriptions) = self.transcriptions.lock() {
+            transcriptions.take();
+        }
+
+        let transcriber = self
+            .transcriber
+            .lock()
+            .ok()
+            .and_then(|mut transcriber| transcriber.take());
+        if let Some(mut transcriber) = transcriber {
+            if tokio::time::timeout(limit, &mut transcriber).await.is_err() {
+                warn!(
+                    "Gave up on {} slices still waiting to be transcribed",
+                    self.stats.queued.load(Ordering::Relaxed)
+                );
+                transcriber.abort();
+            }
+        }
+    }
+
+    pub fn dir_name(&self) -> String {
+        self.dir.to_string_lossy().into_owned()
+    }
+
+    fn summary(&self) -> String {
+        let stats = &self.stats;
+        let elapsed = (Utc::now() - self.started).to_std().unwrap_or_default();
+        let speech = std::time::Duration::from_millis(stats.speech_ms.load(Ordering::Relaxed));
+        let (channel, lines) = match self.transcript.lock() {
+            Ok(transcript) => (transcript.channel_id(), transcript.lines.len()),
+            Err(_) => (None, 0),
+        };
+
+        format!(
+            "{}\nUp {}, {} slices ({} of speech, {} dropped as silence)\n{} transcribed, {} queued, {} failed, {} lines",
+            channel.map_or("Not connected".to_string(), |id| format!("<#{id}>")),
+            format_duration(elapsed),
+            stats.slices.load(Ordering::Relaxed),
+            format_duration(speech),
+            stats.dropped.load(Ordering::Relaxed),
+            stats.transcribed.load(Ordering::Relaxed),
+            stats.queued.load(Ordering::Relaxed),
+            stats.failed.load(Ordering::Relaxed),
+            lines,
+        )
+    }
+}
+
+pub async fn get_sessions(ctx: &Context) -> Arc<DashMap<GuildId, Arc<Session>>> {
+    let data = ctx.data.read().await;
+    data.get::<SessionKey>()
+        .cloned()
+        .expect("Sessions not found")
+}
+
+pub async fn get_session(ctx: &Context, guild_id: GuildId) -> Option<Arc<Session>> {
+    get_sessions(ctx)
+        .await
+        .get(&guild_id)
+        .map(|session| session.clone())
+}
+
+#[command]
+#[owners_only]
+pub async fn sessions(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let mut sessions: Vec<Arc<Session>> = get_sessions(ctx)
+        .await
+        .iter()
+        .map(|entry| entry.value().clone())
+        .collect();
+
+    if sessions.is_empty() {
+        let _ = msg.channel_id.say(&ctx.http, "No active sessions.").await;
+        return Ok(());
+    }
+
+    sessions.sort_by_key(|session| session.started);
+
+    let mut embed = CreateEmbed::new().title(format!("Active sessions ({})", sessions.len()));
+    for session in &sessions {
+        let name = ctx
+            .cache
+            .guild(session.guild_id)
+            .map(|guild| guild.name.clone())
+            .unwrap_or_else(|| session.guild_id.to_string());
+        embed = embed.field(name, session.summary(), false);
+    }
+
+    let _ = msg
+        .channel_id
+        .send_message(&ctx.http, CreateMessage::new().embed(embed))
+        .await;
+
+    Ok(())
+}
@@ -0,0 +1,274 @@
+use std::str::FromStr;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serenity::all::GuildId;
+use serenity::client::Context;
+use serenity::framework::standard::macros::command;
+use serenity::framework::standard::{Args, CommandResult};
+use serenity::model::channel::Message;
+
+use crate::store::{get_store, guild_lock};
+
+const TABLE: &str = "guilds";
+
+pub const MAX_VOLUME_PCT: u16 = 200;
+const MAX_CROSSFADE_MS: u64 = 12_000;
+
+/// What to do with speech that was captured while the bot itself was playing
+/// audio, since it likely contains the bot's own TTS or music.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EchoPolicy {
+    /// Transcribe and act on it like any other speech
+    Record,
+    /// Transcribe it, but never treat it as a voice command or question
+    #[default]
+    SkipTrigger,
+    /// Keep the recording, but don't transcribe it at all
+    SkipTranscription,
+}
+
+impl FromStr for EchoPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "record" => Ok(Self::Record),
+            "skip_trigger" => Ok(Self::SkipTrigger),
+            "skip_transcription" => Ok(Self::SkipTranscription),
+            _ => Err("expected one of: record, skip_trigger, skip_transcription".to_string()),
+        }
+    }
+}
+
+/// What to do with a TTS reply when someone starts talking over it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BargeIn {
+    /// Keep talking
+    Off,
+    /// Fade the reply out, then pause it
+    #[default]
+    Fade,
+    /// Pause the reply immediately
+    Pause,
+}
+
+impl FromStr for BargeIn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "fade" => Ok(Self::Fade),
+            "pause" => Ok(Self::Pause),
+            _ => Err("expected one of: off, fade, pause".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuildSettings {
+    /// Respond to spoken commands and questions picked up by transcription
+    pub voice_triggers: bool,
+    pub echo_policy: EchoPolicy,
+    pub barge_in: BargeIn,
+    /// Resume an interrupted reply if the interruption was shorter than this (0 = never)
+    pub barge_in_resume_ms: u64,
+    /// How far to lower music while someone is talking (0 = no ducking)
+    pub duck_depth_pct: u8,
+    pub duck_attack_ms: u64,
+    pub duck_release_ms: u64,
+    /// Music volume, where 100 is the default level
+    pub volume_pct: u16,
+    /// Fade between queued tracks over this long (0 = no crossfade)
+    pub crossfade_ms: u64,
+    /// Even out loudness between tracks
+    pub normalize: bool,
+    /// Loudness to normalize tracks to
+    pub target_lufs: f32,
+    /// Restore the last saved queue whenever the bot joins
+    pub auto_restore: bool,
+    /// Most tracks to queue from a single playlist URL
+    pub playlist_limit: usize,
+    /// Leave voice after this long alone or with nothing playing or said, or
+    /// never if 0
+    pub idle_timeout_secs: u64,
+    /// Say out loud that recording has started when joining, on top of the
+    /// message in chat
+    pub spoken_announcement: bool,
+    /// Delete unpinned recordings after this many days, or never if 0
+    pub retention_days: u32,
+    /// Trim silence from recorded slices, split them on long pauses and drop
+    /// the ones that are only noise
+    pub vad: bool,
+    /// Audio quieter than this (in dBFS) counts as silence
+    pub vad_threshold_db: f32,
+    /// Split a slice on pauses at least this long
+    pub split_silence_ms: u64,
+    /// Drop pieces of a slice with less speech than this
+    pub min_speech_ms: u64,
+}
+
+impl Default for GuildSettings {
+    fn default() -> Self {
+        Self {
+            voice_triggers: false,
+            echo_policy: EchoPolicy::default(),
+            barge_in: BargeIn::default(),
+            barge_in_resume_ms: 1000,
+            duck_depth_pct: 60,
+            duck_attack_ms: 100,
+            duck_release_ms: 800,
+            volume_pct: 100,
+            crossfade_ms: 0,
+            normalize: false,
+            target_lufs: -14.0,
+            auto_restore: false,
+            playlist_limit: 50,
+            idle_timeout_secs: 300,
+            spoken_announcement: true,
+            retention_days: 30,
+            vad: true,
+            vad_threshold_db: -45.0,
+            split_silence_ms: 700,
+            min_speech_ms: 300,
+        }
+    }
+}
+
+impl GuildSettings {
+    /// Sets a setting from its name and a user provided value.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "voice_triggers" => self.voice_triggers = parse_bool(value)?,
+            "echo_policy" => self.echo_policy = value.parse()?,
+            "barge_in" => self.barge_in = value.parse()?,
+            "barge_in_resume_ms" => self.barge_in_resume_ms = parse_num(value)?,
+            "duck_depth_pct" => self.duck_depth_pct = parse_num::<u8>(value)?.min(100),
+            "duck_attack_ms" => self.duck_attack_ms = parse_num(value)?,
+            "duck_release_ms" => self.duck_release_ms = parse_num(value)?,
+            "volume_pct" => {
+                let volume_pct = parse_num(value.trim_end_matches('%'))?;
+                if volume_pct > MAX_VOLUME_PCT {
+                    return Err(format!("volume can't be more than {MAX_VOLUME_PCT}%"));
+                }
+                self.volume_pct = volume_pct;
+            }
+            "crossfade_ms" => self.crossfade_ms = parse_num::<u64>(value)?.min(MAX_CROSSFADE_MS),
+            "normalize" => self.normalize = parse_bool(value)?,
+            "target_lufs" => self.target_lufs = parse_num::<f32>(value)?.clamp(-40.0, 0.0),
+            "auto_restore" => self.auto_restore = parse_bool(value)?,
+            "playlist_limit" => self.playlist_limit = parse_num::<usize>(value)?.max(1),
+            "idle_timeout_secs" => self.idle_timeout_secs = parse_num(value)?,
+            "spoken_announcement" => self.spoken_announcement = parse_bool(value)?,
+            "retention_days" => self.retention_days = parse_num(value)?,
+            "vad" => self.vad = parse_bool(value)?,
+            "vad_threshold_db" => {
+                self.vad_threshold_db = parse_num::<f32>(value)?.clamp(-90.0, 0.0)
+            }
+            "split_silence_ms" => self.split_silence_ms = parse_num(value)?,
+            "min_speech_ms" => self.min_speech_ms = parse_num(value)?,
+            _ => return Err(format!("unknown setting `{name}`")),
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "true" | "yes" | "1" => Ok(true),
+        "off" | "false" | "no" | "0" => Ok(false),
+        _ => Err("expected on or off".to_string()),
+    }
+}
+
+fn parse_num<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("`{value}` is not a valid number"))
+}
+
+/// Whether the message's author may change the guild's settings, for things
+/// that change them as a side effect. The same check as
+/// `#[required_permissions(MANAGE_GUILD)]`.
+pub fn can_manage_guild(ctx: &Context, msg: &Message) -> bool {
+    msg.guild(&ctx.cache).is_some_and(|guild| {
+        let Some(channel) = guild.channels.get(&msg.channel_id) else {
+            return false;
+        };
+        let Some(member) = guild.members.get(&msg.author.id) else {
+            return false;
+        };
+
+        guild.user_permissions_in(channel, member).manage_guild()
+    })
+}
+
+pub async fn guild_settings(ctx: &Context, guild_id: GuildId) -> GuildSettings {
+    get_store(ctx)
+        .await
+        .get(TABLE, &guild_id.to_string())
+        .unwrap_or_default()
+}
+
+pub async fn update_guild_settings(
+    ctx: &Context,
+    guild_id: GuildId,
+    f: impl FnOnce(&mut GuildSettings) -> Result<(), String>,
+) -> Result<GuildSettings, String> {
+    let lock = guild_lock(ctx, guild_id).await;
+    let _guard = lock.lock().await;
+
+    let store = get_store(ctx).await;
+    let mut settings: GuildSettings = store.get(TABLE, &guild_id.to_string()).unwrap_or_default();
+
+    f(&mut settings)?;
+
+    if let Err(e) = store.put(TABLE, &guild_id.to_string(), &settings) {
+        error!("Failed to save guild settings: {:?}", e);
+        return Err("failed to save settings".to_string());
+    }
+
+    Ok(settings)
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn settings(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let settings = guild_settings(ctx, msg.guild_id.unwrap()).await;
+    let json = serde_json::to_string_pretty(&settings)?;
+
+    let _ = msg
+        .channel_id
+        .say(&ctx.http, format!("```json\n{json}\n```"))
+        .await;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(MANAGE_GUILD)]
+pub async fn set(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let name = args.single::<String>().unwrap_or_default();
+    let value = args.rest().trim().to_lowercase();
+
+    info!("Setting {} = {}", name, value);
+
+    let res = update_guild_settings(ctx, msg.guild_id.unwrap(), |settings| {
+        settings.set(&name, &value)
+    })
+    .await;
+
+    let reply = match res {
+        Ok(_) => format!("`{name}` set to `{value}`"),
+        Err(e) => format!("Couldn't set `{name}`: {e}"),
+    };
+    let _ = msg.channel_id.say(&ctx.http, reply).await;
+
+    Ok(())
+}
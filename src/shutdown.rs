@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serenity::all::GuildId;
+use serenity::client::Context;
+use serenity::framework::standard::macros::hook;
+use serenity::futures::future::join_all;
+use serenity::model::channel::Message;
+
+use crate::history::{History, HISTORY_KEY, HISTORY_TABLE};
+use crate::idle::notice_channel;
+use crate::session::get_sessions;
+use crate::state::{ShardManagerContainer, ShutdownKey};
+use crate::store::get_store;
+use crate::voice::leave_guild;
+
+/// `docker stop` kills the container 10 seconds after SIGTERM, so everything
+/// has to be wrapped up well before then.
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(8);
+/// How long slices still waiting to be transcribed get while shutting down,
+/// leaving time to save the transcript and recap it.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for ctrl+c, or SIGTERM, which is what `docker stop` sends.
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut term = signal(SignalKind::terminate()).expect("Could not register SIGTERM handler");
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.expect("Could not register ctrl+c handler"),
+            _ = term.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Could not register ctrl+c handler");
+}
+
+pub async fn is_shutting_down(ctx: &Context) -> bool {
+    let data = ctx.data.read().await;
+    data.get::<ShutdownKey>()
+        .is_some_and(|flag| flag.load(Ordering::SeqCst))
+}
+
+/// Framework hook that turns commands away once shutdown has started.
+#[hook]
+pub async fn accept_command(ctx: &Context, _msg: &Message, _command_name: &str) -> bool {
+    !is_shutting_down(ctx).await
+}
+
+/// Winds everything down in order: stops taking commands, leaves every voice
+/// channel (which saves in-progress slices, transcripts and queues), saves the
+/// chat history and only then stops the shards.
+pub async fn shutdown(ctx: &Context, history: &Arc<Mutex<History>>) {
+    {
+        let data = ctx.data.read().await;
+        if let Some(flag) = data.get::<ShutdownKey>() {
+            // A second signal while shutting down shouldn't start over
+            if flag.swap(true, Ordering::SeqCst) {
+                return;
+            }
+        }
+    }
+
+    info!("Shutting down");
+
+    let mut guilds: HashSet<GuildId> = HashSet::new();
+    let manager = songbird::get(ctx).await.unwrap().clone();
+    guilds.extend(
+        manager
+            .iter()
+            .map(|(guild_id, _)| GuildId::new(guild_id.0.get())),
+    );
+    guilds.extend(get_sessions(ctx).await.iter().map(|entry| *entry.key()));
+
+    let leave_all = join_all(guilds.into_iter().map(|guild_id| async move {
+        info!("Closing session in guild {}", guild_id);
+        let notice_channel = notice_channel(ctx, guild_id).await;
+        leave_guild(ctx, guild_id, notice_channel).await;
+    }));
+    if tokio::time::timeout(LEAVE_TIMEOUT, leave_all)
+        .await
+        .is_err()
+    {
+        warn!("Timed out closing sessions");
+    }
+
+    let history = history.lock().ok().map(|history| history.clone());
+    if let Some(history) = history {
+        if let Err(e) = get_store(ctx)
+            .await
+            .put(HISTORY_TABLE, HISTORY_KEY, &history)
+        {
+            error!("Failed to save history: {:?}", e);
+        }
+    }
+
+    let shard_manager = {
+        let data = ctx.data.read().await;
+        data.get::<ShardManagerContainer>().cloned()
+    };
+    if let Some(shard_manager) = shard_manager {
+        shard_manager.shutdown_all().await;
+    }
+}
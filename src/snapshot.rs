@@ -0,0 +1,197 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serenity::all::{GuildId, UserId};
+use serenity::async_trait;
+use serenity::client::Context;
+use serenity::framework::standard::macros::command;
+use serenity::framework::standard::{Args, CommandResult};
+use serenity::model::channel::Message;
+use songbird::{Event, EventContext as Ctx, EventHandler};
+
+use crate::controls::caller_call;
+use crate::music::{enqueue, format_duration, get_track_info};
+use crate::playlists::SavedTrack;
+use crate::settings::{can_manage_guild, guild_settings, update_guild_settings};
+use crate::store::get_store;
+
+const TABLE: &str = "queues";
+
+/// How often the queue of each guild the bot is playing in gets saved.
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotTrack {
+    #[serde(flatten)]
+    track: SavedTrack,
+    requester: Option<UserId>,
+}
+
+/// A guild's queue as it was when last saved, so it survives restarts.
+#[derive(Serialize, Deserialize)]
+struct QueueSnapshot {
+    saved: DateTime<Utc>,
+    /// How far into the first track playback had got
+    position: Duration,
+    volume_pct: u16,
+    tracks: Vec<SnapshotTrack>,
+}
+
+/// Saves the guild's current queue, overwriting the last snapshot. An empty
+/// queue only overwrites it if `allow_empty` is set. Returns how many tracks
+/// were in the queue.
+pub async fn save_queue(ctx: &Context, guild_id: GuildId, allow_empty: bool) -> usize {
+    let manager = songbird::get(ctx).await.unwrap().clone();
+    let Some(call) = manager.get(guild_id) else {
+        return 0;
+    };
+    let queue = call.lock().await.queue().current_queue();
+    if queue.is_empty() && !allow_empty {
+        return 0;
+    }
+
+    let position = match queue.first() {
+        Some(track) => track
+            .get_info()
+            .await
+            .map(|state| state.position)
+            .unwrap_or_default(),
+        None => Duration::ZERO,
+    };
+
+    let mut tracks = Vec::new();
+    for track in &queue {
+        // TTS replies and other non-music tracks aren't worth restoring
+        if let Some(info) = get_track_info(track).await {
+            tracks.push(SnapshotTrack {
+                requester: info.requester,
+                track: info.into(),
+            });
+        }
+    }
+
+    let snapshot = QueueSnapshot {
+        saved: Utc::now(),
+        position,
+        volume_pct: guild_settings(ctx, guild_id).await.volume_pct,
+        tracks,
+    };
+
+    if let Err(e) = get_store(ctx)
+        .await
+        .put(TABLE, &guild_id.to_string(), &snapshot)
+    {
+        error!("Failed to save queue snapshot: {:?}", e);
+    }
+
+    queue.len()
+}
+
+/// Queues everything from the guild's last snapshot and seeks back to where
+/// the first track left off. The saved volume is only brought back if
+/// `restore_volume` is set, since it's a guild setting. Returns how many
+/// tracks were restored and where playback resumes.
+pub async fn restore_queue(
+    ctx: &Context,
+    guild_id: GuildId,
+    restore_volume: bool,
+) -> Option<(usize, String)> {
+    let snapshot: QueueSnapshot = get_store(ctx).await.get(TABLE, &guild_id.to_string())?;
+    let first = snapshot.tracks.first()?;
+    let resume_at = format!(
+        "**{}** at {}",
+        first.track.title,
+        format_duration(snapshot.position)
+    );
+
+    let manager = songbird::get(ctx).await.unwrap().clone();
+    let call = manager.get(guild_id)?;
+
+    info!(
+        "Restoring {} tracks saved at {}",
+        snapshot.tracks.len(),
+        snapshot.saved
+    );
+
+    if restore_volume {
+        let _ = update_guild_settings(ctx, guild_id, |settings| {
+            settings.volume_pct = snapshot.volume_pct;
+            Ok(())
+        })
+        .await;
+    }
+
+    let mut handler = call.lock().await;
+    for (i, saved) in snapshot.tracks.iter().enumerate() {
+        let info = saved.track.info(saved.requester);
+        let input = info.input(ctx).await;
+        let live = info.stream_title.is_some();
+        let handle = enqueue(ctx, guild_id, &mut handler, input, info).await;
+
+        if i == 0 && !live && snapshot.position >= Duration::from_secs(1) {
+            // Applied once the track has loaded
+            let _ = handle.seek(snapshot.position);
+        }
+    }
+
+    Some((snapshot.tracks.len(), resume_at))
+}
+
+/// Periodic global event that keeps the guild's snapshot up to date.
+pub struct QueueSaver {
+    ctx: Context,
+    guild_id: GuildId,
+    /// Set once this session has queued anything, so that joining with an
+    /// empty queue doesn't wipe out the snapshot before it can be restored
+    had_tracks: AtomicBool,
+}
+
+impl QueueSaver {
+    pub fn new(ctx: Context, guild_id: GuildId) -> Self {
+        Self {
+            ctx,
+            guild_id,
+            had_tracks: AtomicBool::new(false),
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for QueueSaver {
+    async fn act(&self, _ctx: &Ctx<'_>) -> Option<Event> {
+        let allow_empty = self.had_tracks.load(Ordering::Relaxed);
+        if save_queue(&self.ctx, self.guild_id, allow_empty).await > 0 {
+            self.had_tracks.store(true, Ordering::Relaxed);
+        }
+        None
+    }
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn restore(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let Some(call) = caller_call(ctx, msg).await else {
+        return Ok(());
+    };
+
+    // Seeking only makes sense if the restored queue starts playing right away
+    if !call.lock().await.queue().is_empty() {
+        let _ = msg
+            .channel_id
+            .say(&ctx.http, "Something is already queued; `~stop` it first.")
+            .await;
+        return Ok(());
+    }
+
+    let restore_volume = can_manage_guild(ctx, msg);
+    let reply = match restore_queue(ctx, msg.guild_id.unwrap(), restore_volume).await {
+        Some((count, resume_at)) => format!("Restored {count} tracks, resuming {resume_at}"),
+        None => "There's no saved queue to restore.".to_string(),
+    };
+    let _ = msg.channel_id.say(&ctx.http, reply).await;
+
+    Ok(())
+}
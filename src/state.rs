@@ -1,9 +1,20 @@
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
+use dashmap::DashMap;
 use reqwest::Client as HttpClient;
+use serenity::all::GuildId;
 use serenity::gateway::ShardManager;
 use songbird::typemap::TypeMapKey;
 
+use crate::consent::Consent;
+use crate::controls::LoopMode;
+use crate::idle::IdleTracker;
+use crate::library::Library;
+use crate::search::SearchProvider;
+use crate::session::Session;
+use crate::store::Store;
+
 pub struct HttpKey;
 
 impl TypeMapKey for HttpKey {
@@ -15,3 +26,67 @@ pub struct ShardManagerContainer;
 impl TypeMapKey for ShardManagerContainer {
     type Value = Arc<ShardManager>;
 }
+
+pub struct SessionKey;
+
+impl TypeMapKey for SessionKey {
+    type Value = Arc<DashMap<GuildId, Arc<Session>>>;
+}
+
+pub struct StoreKey;
+
+impl TypeMapKey for StoreKey {
+    type Value = Arc<Store>;
+}
+
+pub struct LoopModeKey;
+
+impl TypeMapKey for LoopModeKey {
+    type Value = Arc<DashMap<GuildId, LoopMode>>;
+}
+
+pub struct SearchKey;
+
+impl TypeMapKey for SearchKey {
+    type Value = Arc<dyn SearchProvider>;
+}
+
+pub struct LibraryKey;
+
+impl TypeMapKey for LibraryKey {
+    type Value = Arc<Library>;
+}
+
+pub struct IdleKey;
+
+impl TypeMapKey for IdleKey {
+    type Value = Arc<DashMap<GuildId, IdleTracker>>;
+}
+
+/// Set once shutdown has started, so no new commands are taken.
+pub struct ShutdownKey;
+
+impl TypeMapKey for ShutdownKey {
+    type Value = Arc<AtomicBool>;
+}
+
+/// Held while a guild's stored settings, playlists or pins are read, changed
+/// and saved, so concurrent changes don't overwrite each other.
+pub struct GuildLockKey;
+
+impl TypeMapKey for GuildLockKey {
+    type Value = Arc<DashMap<GuildId, Arc<tokio::sync::Mutex<()>>>>;
+}
+
+/// Recordings are encrypted with this, if it's set.
+pub struct RecordingKey;
+
+impl TypeMapKey for RecordingKey {
+    type Value = Option<crypto_secretbox::Key>;
+}
+
+pub struct ConsentKey;
+
+impl TypeMapKey for ConsentKey {
+    type Value = Arc<Consent>;
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Error;
+use dashmap::DashMap;
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serenity::all::GuildId;
+use serenity::client::Context;
+
+use crate::state::{GuildLockKey, StoreKey};
+
+/// Embedded document store for bot state: every value is a JSON file at
+/// `<dir>/<table>/<key>.json`, cached in memory once read.
+pub struct Store {
+    dir: PathBuf,
+    cache: DashMap<String, serde_json::Value>,
+    write_lock: Mutex<()>,
+}
+
+impl Store {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+            cache: DashMap::new(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    fn path(&self, table: &str, key: &str) -> PathBuf {
+        self.dir.join(table).join(format!("{}.json", sanitize(key)))
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, table: &str, key: &str) -> Option<T> {
+        let cache_key = format!("{table}/{}", sanitize(key));
+
+        let value = match self.cache.get(&cache_key) {
+            Some(value) => value.clone(),
+            None => {
+                let bytes = fs::read(self.path(table, key)).ok()?;
+                let value = serde_json::from_slice::<serde_json::Value>(&bytes)
+                    .map_err(|e| error!("Corrupt store entry [{cache_key}]: {}", e))
+                    .ok()?;
+                self.cache.insert(cache_key.clone(), value.clone());
+                value
+            }
+        };
+
+        serde_json::from_value(value)
+            .map_err(|e| error!("Invalid store entry [{cache_key}]: {}", e))
+            .ok()
+    }
+
+    pub fn put<T: Serialize>(&self, table: &str, key: &str, value: &T) -> Result<(), Error> {
+        let value = serde_json::to_value(value)?;
+        let path = self.path(table, key);
+        let tmp = path.with_extension("json.tmp");
+
+        let _lock = self
+            .write_lock
+            .lock()
+            .map_err(|_| Error::msg("Store lock poisoned"))?;
+        fs::create_dir_all(self.dir.join(table))?;
+        // Write then rename so a crash never leaves a half written entry
+        fs::write(&tmp, serde_json::to_vec_pretty(&value)?)?;
+        fs::rename(&tmp, &path)?;
+
+        self.cache
+            .insert(format!("{table}/{}", sanitize(key)), value);
+
+        Ok(())
+    }
+}
+
+/// Keys end up as file names, so only keep characters that are safe there.
+fn sanitize(key: &str) -> String {
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub async fn get_store(ctx: &Context) -> std::sync::Arc<Store> {
+    let data = ctx.data.read().await;
+    data.get::<StoreKey>().cloned().expect("Store not found")
+}
+
+/// The lock to hold while changing something stored for a guild.
+pub async fn guild_lock(
+    ctx: &Context,
+    guild_id: GuildId,
+) -> std::sync::Arc<tokio::sync::Mutex<()>> {
+    let locks = {
+        let data = ctx.data.read().await;
+        data.get::<GuildLockKey>()
+            .cloned()
+            .expect("Guild locks not found")
+    };
+    let lock = locks.entry(guild_id).or_default();
+    lock.clone()
+}
@@ -0,0 +1,250 @@
+use std::{env, fs, mem};
+
+use anyhow::Error;
+use log::{error, info};
+use serenity::all::ChannelId;
+use serenity::client::Context;
+use serenity::framework::standard::macros::command;
+use serenity::framework::standard::{Args, CommandResult};
+use serenity::model::channel::Message;
+
+use crate::cfg::{CHUNK_SUMMARY_PROMPT, RECAP_PROMPT};
+use crate::openai::{build_json_client, ChatMessage, ChatRequest, OPENAI_API_URL};
+use crate::session::get_session;
+use crate::transcript::Transcript;
+
+/// Roughly 3k tokens of transcript per request.
+const CHUNK_CHARS: usize = 12_000;
+
+/// Discord's message length limit.
+const MESSAGE_CHARS: usize = 2000;
+
+pub struct Summarizer {
+    client: reqwest::Client,
+    model: String,
+    template: String,
+}
+
+impl Summarizer {
+    pub fn new() -> Self {
+        let openai_api_key = env::var("OPENAI_API_KEY").unwrap_or_default();
+        let model = env::var("SUMMARY_MODEL")
+            .or_else(|_| env::var("MODEL"))
+            .unwrap_or_else(|_| "gpt-3.5-turbo".to_string());
+
+        let template = match env::var("RECAP_PROMPT_FILE") {
+            Ok(path) => fs::read_to_string(&path).unwrap_or_else(|e| {
+                error!("Failed to read recap prompt [{path}]: {}", e);
+                RECAP_PROMPT.to_string()
+            }),
+            Err(_) => RECAP_PROMPT.to_string(),
+        };
+
+        Self {
+            client: build_json_client(&openai_api_key).expect("Failed to build OpenAI client"),
+            model,
+            template,
+        }
+    }
+
+    /// Summarizes the transcript chunk by chunk until the notes fit in a single
+    /// request, then writes the final recap from them.
+    pub async fn recap(&self, transcript: &Transcript) -> Result<String, Error> {
+        let mut notes = transcript.to_text();
+
+        while notes.len() > CHUNK_CHARS {
+            let chunks = chunk(&notes, CHUNK_CHARS);
+            info!("Summarizing {} transcript chunks", chunks.len());
+
+            let mut summaries = Vec::with_capacity(chunks.len());
+            for chunk in chunks {
+                summaries.push(self.complete(CHUNK_SUMMARY_PROMPT, &chunk).await?);
+            }
+
+            notes = summaries.join("\n\n");
+        }
+
+        let prompt = if self.template.contains("{notes}") {
+            self.template.replace("{notes}", &notes)
+        } else {
+            format!("{}\n\n{}", self.template, notes)
+        };
+
+        self.complete(&prompt, "Write the recap.").await
+    }
+
+    async fn complete(&self, sys_prompt: &str, text: &str) -> Result<String, Error> {
+        let data = self
+            .client
+            .post(format!("{OPENAI_API_URL}/chat/completions"))
+            .json(&ChatRequest {
+                model: self.model.clone(),
+                messages: vec![
+                    ChatMessage::new("system", sys_prompt),
+                    ChatMessage::new("user", text),
+                ],
+            })
+            .send()
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
+
+        data["choices"][0]["message"]["content"]
+            .as_str()
+            .map(|s| s.to_string())
+            .ok_or_else(|| Error::msg(format!("Failed to summarize: {data}")))
+    }
+}
+
+/// Splits text on line boundaries into chunks of at most `size` bytes
+/// (a single longer line gets a chunk to itself).
+fn chunk(text: &str, size: usize) -> Vec<String> {
+    let mut chunks = vec![String::new()];
+
+    for line in text.lines() {
+        let current = chunks.last_mut().unwrap();
+        if !current.is_empty() && current.len() + line.len() + 1 > size {
+            chunks.push(String::new());
+        }
+
+        let current = chunks.last_mut().unwrap();
+        current.push_str(line);
+        current.push('\n');
+    }
+
+    chunks
+}
+
+/// Splits text into messages of at most `limit` bytes, on line boundaries
+/// where possible. Lines too long for one message are cut at a space, or at
+/// any character if there isn't one.
+fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut message = String::new();
+
+    for mut line in text.lines() {
+        while line.len() >= limit {
+            let mut end = limit;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            if let Some(space) = line[..end].rfind(' ').filter(|space| *space > 0) {
+                end = space;
+            }
+
+            if !message.is_empty() {
+                messages.push(mem::take(&mut message));
+            }
+            messages.push(line[..end].to_string());
+            line = line[end..].trim_start();
+        }
+
+        if !message.is_empty() && message.len() + line.len() + 1 > limit {
+            messages.push(mem::take(&mut message));
+        }
+        message.push_str(line);
+        message.push('\n');
+    }
+
+    if !message.trim().is_empty() {
+        messages.push(message);
+    }
+    messages
+}
+
+/// Posts the recap, split up to fit Discord's message limit.
+pub async fn post_recap(ctx: &Context, channel_id: ChannelId, recap: &str) {
+    for message in split_message(recap, MESSAGE_CHARS) {
+        if let Err(e) = channel_id.say(&ctx.http, &message).await {
+            error!("Failed to send recap: {}", e);
+        }
+    }
+}
+
+#[command]
+#[only_in(guilds)]
+pub async fn recap(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+
+    let transcript = get_session(ctx, guild_id)
+        .await
+        .and_then(|session| session.transcript.lock().ok().map(|t| t.clone()));
+
+    let transcript = match transcript {
+        Some(transcript) if !transcript.is_empty() => transcript,
+        _ => {
+            let _ = msg.channel_id.say(&ctx.http, "Nothing to recap yet.").await;
+            return Ok(());
+        }
+    };
+
+    let typing = msg.channel_id.start_typing(&ctx.http);
+
+    match Summarizer::new().recap(&transcript).await {
+        Ok(recap) => post_recap(ctx, msg.channel_id, &recap).await,
+        Err(e) => {
+            error!("Recap error: {:?}", e);
+            let _ = msg
+                .channel_id
+                .say(&ctx.http, "Failed to generate a recap.")
+                .await;
+        }
+    }
+
+    typing.stop();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_on_line_boundaries() {
+        assert_eq!(chunk("a\nb\nc", 4), vec!["a\nb\n", "c\n"]);
+    }
+
+    #[test]
+    fn long_lines_get_a_chunk_to_themselves() {
+        assert_eq!(
+            chunk("a\nlong line\nb", 4),
+            vec!["a\n", "long line\n", "b\n"]
+        );
+    }
+
+    #[test]
+    fn keeps_all_the_text() {
+        let text: String = (0..500)
+            .map(|i| format!("[00:00:{i:02}] someone: line {i}\n"))
+            .collect();
+        let chunks = chunk(&text, 1000);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 1000));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn messages_fit_the_limit() {
+        let text = format!("intro\n{}\nend", "word ".repeat(1000));
+        let messages = split_message(&text, 2000);
+
+        assert!(messages.iter().all(|message| message.len() <= 2000));
+        assert_eq!(messages.first().map(String::as_str), Some("intro\n"));
+        let words: usize = messages
+            .iter()
+            .map(|message| message.split_whitespace().count())
+            .sum();
+        assert_eq!(words, text.split_whitespace().count());
+    }
+
+    #[test]
+    fn cuts_unbroken_lines_on_char_boundaries() {
+        let text = "é".repeat(1500);
+        let messages = split_message(&text, 2000);
+
+        assert!(messages.iter().all(|message| message.len() <= 2000));
+        assert_eq!(messages.concat().replace('\n', ""), text);
+    }
+}
@@ -0,0 +1,295 @@
+use std::env;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use crypto_secretbox::Key;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serenity::all::{ChannelId, GuildId};
+use serenity::builder::{CreateAttachment, CreateMessage};
+use serenity::client::Context;
+
+use crate::encryption;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TranscriptLine {
+    pub user_id: Option<u64>,
+    pub ssrc: u32,
+    pub speaker: String,
+    pub timestamp: DateTime<Utc>,
+    pub text: String,
+    /// Captured while the bot was playing audio, so it may contain echo
+    #[serde(default)]
+    pub bot_playback: bool,
+}
+
+/// Something that happened to the bot's voice connection during a session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ConnectionEvent {
+    Joined {
+        channel_id: u64,
+    },
+    /// Someone dragged the bot to another channel, starting a new segment
+    Moved {
+        channel_id: u64,
+    },
+    /// The voice connection dropped and songbird got it back
+    Reconnected,
+    /// The voice connection dropped, for the given reason
+    Dropped {
+        reason: String,
+    },
+    /// Someone disconnected the bot from voice
+    Disconnected,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectionChange {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: ConnectionEvent,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transcript {
+    pub guild_id: u64,
+    pub started: DateTime<Utc>,
+    pub ended: Option<DateTime<Utc>>,
+    pub lines: Vec<TranscriptLine>,
+    #[serde(default)]
+    pub connection: Vec<ConnectionChange>,
+}
+
+impl Transcript {
+    pub fn new(guild_id: GuildId) -> Self {
+        Self {
+            guild_id: guild_id.get(),
+            started: Utc::now(),
+            ended: None,
+            lines: Vec::new(),
+            connection: Vec::new(),
+        }
+    }
+
+    pub fn log_connection(&mut self, event: ConnectionEvent) {
+        self.connection.push(ConnectionChange {
+            timestamp: Utc::now(),
+            event,
+        });
+    }
+
+    /// The channel the bot was last put in, if the session is still in one.
+    pub fn channel_id(&self) -> Option<u64> {
+        let last = self.connection.iter().rev().find(|change| {
+            matches!(
+                change.event,
+                ConnectionEvent::Joined { .. }
+                    | ConnectionEvent::Moved { .. }
+                    | ConnectionEvent::Disconnected
+            )
+        })?;
+
+        match last.event {
+            ConnectionEvent::Joined { channel_id } | ConnectionEvent::Moved { channel_id } => {
+                Some(channel_id)
+            }
+            _ => None,
+        }
+    }
+
+    /// Slices finish transcribing out of order, so keep the lines sorted by
+    /// when the speech started rather than when the text came back.
+    pub fn push(&mut self, line: TranscriptLine) {
+        let index = self
+            .lines
+            .partition_point(|l| l.timestamp <= line.timestamp);
+        self.lines.insert(index, line);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Time since the start of the session, formatted as `HH:MM:SS`.
+    fn offset(&self, timestamp: DateTime<Utc>) -> String {
+        let secs = (timestamp - self.started).num_seconds().max(0);
+        format!(
+            "{:02}:{:02}:{:02}",
+            secs / 3600,
+            (secs / 60) % 60,
+            secs % 60
+        )
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "Voice session {}\n\n",
+            self.started.format("%Y-%m-%d %H:%M UTC")
+        );
+
+        for line in &self.lines {
+            text.push_str(&format!(
+                "[{}] {}: {}\n",
+                self.offset(line.timestamp),
+                line.speaker,
+                line.text.trim()
+            ));
+        }
+
+        text
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut md = format!(
+            "# Voice session {}\n\n",
+            self.started.format("%Y-%m-%d %H:%M UTC")
+        );
+
+        if let Some(ended) = self.ended {
+            md.push_str(&format!("Duration: {}\n\n", self.offset(ended)));
+        }
+
+        for line in &self.lines {
+            md.push_str(&format!(
+                "`{}` **{}:** {}\n\n",
+                self.offset(line.timestamp),
+                line.speaker,
+                line.text.trim()
+            ));
+        }
+
+        md
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// Writes the transcript next to the recordings in `dir`, in every format.
+    /// With nothing said, only the JSON is written, for its connection events.
+    pub fn save(&self, dir: &str, key: Option<&Key>) {
+        let name = format!("{}/transcript_{}", dir, self.started.timestamp_millis());
+
+        for (ext, contents) in self.exports() {
+            if self.is_empty() && ext != "json" {
+                continue;
+            }
+
+            let path = format!("{name}.{ext}");
+            if let Err(e) = encryption::write_file(Path::new(&path), contents.as_bytes(), key) {
+                error!("Failed to save transcript: {:?}", e);
+            }
+        }
+    }
+
+    fn exports(&self) -> [(&'static str, String); 3] {
+        [
+            ("md", self.to_markdown()),
+            ("txt", self.to_text()),
+            ("json", self.to_json()),
+        ]
+    }
+
+    /// Posts the transcript to `TRANSCRIPT_CHANNEL_ID` as file attachments.
+    pub async fn post(&self, ctx: &Context) {
+        let Some(channel_id) = transcript_channel() else {
+            info!("TRANSCRIPT_CHANNEL_ID not set, not posting transcript");
+            return;
+        };
+
+        let date = self.started.format("%Y-%m-%d");
+        let files = self.exports().into_iter().map(|(ext, contents)| {
+            CreateAttachment::bytes(contents, format!("session_{date}.{ext}"))
+        });
+
+        let message = CreateMessage::new()
+            .content(format!(
+                "Session transcript: {} lines from {}",
+                self.lines.len(),
+                self.started.format("%Y-%m-%d %H:%M UTC")
+            ))
+            .add_files(files);
+
+        if let Err(e) = channel_id.send_message(&ctx.http, message).await {
+            error!("Failed to post transcript: {}", e);
+        }
+    }
+}
+
+pub fn transcript_channel() -> Option<ChannelId> {
+    env::var("TRANSCRIPT_CHANNEL_ID")
+        .ok()
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(ChannelId::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(transcript: &Transcript, secs: i64, text: &str) -> TranscriptLine {
+        TranscriptLine {
+            user_id: Some(1),
+            ssrc: 1,
+            speaker: "alice".to_string(),
+            timestamp: transcript.started + chrono::Duration::seconds(secs),
+            text: text.to_string(),
+            bot_playback: false,
+        }
+    }
+
+    fn texts(transcript: &Transcript) -> Vec<&str> {
+        transcript
+            .lines
+            .iter()
+            .map(|line| line.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_lines_in_spoken_order() {
+        let mut transcript = Transcript::new(GuildId::new(1));
+        transcript.push(line(&transcript, 10, "third"));
+        transcript.push(line(&transcript, 1, "first"));
+        transcript.push(line(&transcript, 5, "second"));
+        transcript.push(line(&transcript, 10, "fourth"));
+
+        assert_eq!(
+            texts(&transcript),
+            vec!["first", "second", "third", "fourth"]
+        );
+    }
+
+    #[test]
+    fn exports_offsets_from_the_start() {
+        let mut transcript = Transcript::new(GuildId::new(1));
+        transcript.push(line(&transcript, 3725, " hello "));
+        transcript.ended = Some(transcript.started + chrono::Duration::seconds(3800));
+
+        assert!(transcript.to_text().contains("[01:02:05] alice: hello\n"));
+
+        let md = transcript.to_markdown();
+        assert!(md.contains("Duration: 01:03:20"));
+        assert!(md.contains("`01:02:05` **alice:** hello"));
+
+        let json: Transcript = serde_json::from_str(&transcript.to_json()).unwrap();
+        assert_eq!(texts(&json), vec![" hello "]);
+    }
+
+    #[test]
+    fn tracks_the_current_channel() {
+        let mut transcript = Transcript::new(GuildId::new(1));
+        assert_eq!(transcript.channel_id(), None);
+
+        transcript.log_connection(ConnectionEvent::Joined { channel_id: 5 });
+        transcript.log_connection(ConnectionEvent::Reconnected);
+        assert_eq!(transcript.channel_id(), Some(5));
+
+        transcript.log_connection(ConnectionEvent::Moved { channel_id: 6 });
+        assert_eq!(transcript.channel_id(), Some(6));
+
+        transcript.log_connection(ConnectionEvent::Disconnected);
+        assert_eq!(transcript.channel_id(), None);
+    }
+}
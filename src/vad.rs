@@ -0,0 +1,188 @@
+use std::ops::Range;
+
+/// Slices are interleaved 48kHz stereo.
+const SAMPLES_PER_MS: usize = 96;
+/// Energy is measured over frames this long, the same as a voice packet.
+const FRAME_MS: usize = 20;
+const FRAME_SAMPLES: usize = FRAME_MS * SAMPLES_PER_MS;
+/// Kept around each stretch of speech so quiet word onsets and endings
+/// aren't clipped.
+const PAD_MS: usize = 200;
+
+/// How a slice gets cut up, from the guild's settings.
+pub struct VadConfig {
+    /// Frames quieter than this count as silence
+    pub threshold_db: f32,
+    /// Split the slice on pauses at least this long
+    pub split_silence_ms: u64,
+    /// Drop pieces with less speech than this
+    pub min_speech_ms: u64,
+}
+
+/// Loudness of a frame in dBFS.
+fn frame_db(frame: &[i16]) -> f32 {
+    if frame.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+
+    let sum: f64 = frame
+        .iter()
+        .map(|&sample| {
+            let sample = sample as f64 / i16::MAX as f64;
+            sample * sample
+        })
+        .sum();
+    let rms = (sum / frame.len() as f64).sqrt();
+
+    (20.0 * rms.max(1e-9).log10()) as f32
+}
+
+/// A stretch of frames, with how many of them were loud enough to be speech.
+struct Segment {
+    frames: Range<usize>,
+    voiced: usize,
+}
+
+/// Finds the parts of `pcm` worth keeping, as sample ranges: leading and
+/// trailing silence is trimmed, long pauses split the slice in two, and
+/// pieces that are mostly breathing or background noise are left out.
+pub fn speech_segments(pcm: &[i16], config: &VadConfig) -> Vec<Range<usize>> {
+    let voiced: Vec<bool> = pcm
+        .chunks(FRAME_SAMPLES)
+        .map(|frame| frame_db(frame) >= config.threshold_db)
+        .collect();
+
+    let max_gap = (config.split_silence_ms as usize / FRAME_MS).max(1);
+    let mut segments: Vec<Segment> = Vec::new();
+    for (i, _) in voiced.iter().enumerate().filter(|(_, voiced)| **voiced) {
+        match segments.last_mut() {
+            Some(segment) if i - segment.frames.end < max_gap => {
+                segment.frames.end = i + 1;
+                segment.voiced += 1;
+            }
+            _ => segments.push(Segment {
+                frames: i..i + 1,
+                voiced: 1,
+            }),
+        }
+    }
+
+    let min_frames = config.min_speech_ms as usize / FRAME_MS;
+    let pad = PAD_MS * SAMPLES_PER_MS;
+
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for segment in segments
+        .into_iter()
+        .filter(|segment| segment.voiced >= min_frames.max(1))
+    {
+        // Padding never reaches back into the previous piece
+        let previous_end = ranges.last().map_or(0, |range| range.end);
+        let start = (segment.frames.start * FRAME_SAMPLES)
+            .saturating_sub(pad)
+            .max(previous_end);
+        let end = (segment.frames.end * FRAME_SAMPLES + pad).min(pcm.len());
+        ranges.push(start..end);
+    }
+
+    ranges
+}
+
+/// Milliseconds of audio in `samples` samples.
+pub fn samples_to_ms(samples: usize) -> i64 {
+    (samples / SAMPLES_PER_MS) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: VadConfig = VadConfig {
+        threshold_db: -45.0,
+        split_silence_ms: 700,
+        min_speech_ms: 300,
+    };
+
+    fn silence(ms: usize) -> Vec<i16> {
+        vec![0; ms * SAMPLES_PER_MS]
+    }
+
+    /// A loud square wave, well above the threshold.
+    fn speech(ms: usize) -> Vec<i16> {
+        (0..ms * SAMPLES_PER_MS)
+            .map(|i| if i % 96 < 48 { 8000 } else { -8000 })
+            .collect()
+    }
+
+    fn concat(parts: &[Vec<i16>]) -> Vec<i16> {
+        parts.concat()
+    }
+
+    #[test]
+    fn trims_leading_and_trailing_silence() {
+        let pcm = concat(&[silence(1000), speech(500), silence(1000)]);
+        let segments = speech_segments(&pcm, &CONFIG);
+
+        let start = (1000 - PAD_MS) * SAMPLES_PER_MS;
+        let end = (1500 + PAD_MS) * SAMPLES_PER_MS;
+        assert_eq!(segments, vec![start..end]);
+    }
+
+    #[test]
+    fn padding_stops_at_the_edges() {
+        let pcm = concat(&[silence(100), speech(500), silence(100)]);
+        assert_eq!(speech_segments(&pcm, &CONFIG), vec![0..pcm.len()]);
+    }
+
+    #[test]
+    fn splits_on_long_pauses() {
+        let pcm = concat(&[speech(500), silence(1000), speech(500)]);
+        let segments = speech_segments(&pcm, &CONFIG);
+
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0], 0..(500 + PAD_MS) * SAMPLES_PER_MS);
+        assert_eq!(segments[1], (1500 - PAD_MS) * SAMPLES_PER_MS..pcm.len());
+    }
+
+    #[test]
+    fn keeps_short_pauses_together() {
+        let pcm = concat(&[speech(500), silence(300), speech(500)]);
+        assert_eq!(speech_segments(&pcm, &CONFIG), vec![0..pcm.len()]);
+    }
+
+    #[test]
+    fn padding_never_overlaps() {
+        let config = VadConfig {
+            split_silence_ms: 100,
+            ..CONFIG
+        };
+        let pcm = concat(&[speech(500), silence(200), speech(500)]);
+        let segments = speech_segments(&pcm, &config);
+
+        assert_eq!(segments.len(), 2);
+        assert!(segments[0].end <= segments[1].start);
+        assert_eq!(segments[1].end, pcm.len());
+    }
+
+    #[test]
+    fn drops_short_noise() {
+        let pcm = concat(&[silence(500), speech(100), silence(500)]);
+        assert!(speech_segments(&pcm, &CONFIG).is_empty());
+
+        // Only the piece with enough speech survives a split
+        let pcm = concat(&[speech(100), silence(1000), speech(500)]);
+        let segments = speech_segments(&pcm, &CONFIG);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].end, pcm.len());
+    }
+
+    #[test]
+    fn silence_and_empty_input_have_no_segments() {
+        assert!(speech_segments(&silence(2000), &CONFIG).is_empty());
+        assert!(speech_segments(&[], &CONFIG).is_empty());
+    }
+
+    #[test]
+    fn converts_samples_to_ms() {
+        assert_eq!(samples_to_ms(96 * 250), 250);
+    }
+}
@@ -1,59 +1,92 @@
 use std::collections::HashSet;
-use std::fs::OpenOptions;
+use std::env;
 use std::fs::{self};
-use std::io::Write;
+use std::io::Cursor;
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::Error;
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
 use hound::{SampleFormat, WavSpec, WavWriter};
 use log::{error, info, warn};
 use reqwest::multipart::{Form, Part};
-use serenity::all::GuildId;
+use serenity::all::{ChannelId, GuildId};
 use serenity::client::Context;
-use serenity::gateway::ActivityData;
 use serenity::model::channel::Message;
 use serenity::{async_trait, FutureExt};
-use songbird::events::context_data::VoiceData;
+use songbird::events::context_data::{DisconnectData, VoiceData};
 use songbird::input::codecs::{CODEC_REGISTRY, PROBE};
 use songbird::input::Input;
 use songbird::model::id::UserId;
 use songbird::model::payload::{ClientDisconnect, Speaking};
 use songbird::packet::wrap::Wrap32;
 use songbird::packet::{discord, rtcp};
-use songbird::{CoreEvent, Event, EventContext as Ctx, EventHandler};
+use songbird::tracks::TrackHandle;
+use songbird::{Call, CoreEvent, Event, EventContext as Ctx, EventHandler, TrackEvent};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
 
 use crate::bot::Bot;
 use crate::cfg::SYS_PROMPT;
-use crate::music::find_song;
+use crate::consent::{
+    audit, is_opted_out, update_status, ConsentAction, ANNOUNCEMENT, SPOKEN_ANNOUNCEMENT,
+};
+use crate::controls::QueueLooper;
+use crate::ducking::Ducker;
+use crate::encryption::{self, get_recording_key};
+use crate::idle::{notice_channel, set_active, unwatch, watch};
+use crate::music::{enqueue, find_song, get_track_volume, TrackInfo};
 use crate::openai::{
     build_json_client, build_multipart_client, ChatMessage, ChatRequest, SpeechRequest,
     OPENAI_API_URL,
 };
+use crate::playback::{Playback, PlaybackMonitor, PLAYBACK_EVENTS};
+use crate::retention::{enforce, low_space_warning};
+use crate::session::{get_session, get_sessions, Session, Transcription};
+use crate::settings::{can_manage_guild, guild_settings, BargeIn, EchoPolicy, GuildSettings};
+use crate::shutdown::{is_shutting_down, DRAIN_TIMEOUT};
+use crate::snapshot::{restore_queue, save_queue, QueueSaver, SNAPSHOT_INTERVAL};
+use crate::summary::{post_recap, Summarizer};
+use crate::transcript::{transcript_channel, ConnectionEvent, TranscriptLine};
+use crate::vad::{samples_to_ms, speech_segments, VadConfig};
+
+const SPEECH_VOLUME: f32 = 0.5;
+const FADE_DURATION: Duration = Duration::from_millis(300);
+const FADE_STEPS: u32 = 10;
+/// How long leaving waits for queued slices to be transcribed.
+const TRANSCRIPTION_DRAIN_TIMEOUT: Duration = Duration::from_secs(120);
 
 #[derive(Clone)]
-struct Receiver {
+pub struct Receiver {
     ctx: Context,
-    guild_id: GuildId,
+    session: Arc<Session>,
     chat_model: String,
     json_client: reqwest::Client,
     multipart_client: reqwest::Client,
-    controller: Arc<VoiceController>,
-}
-
-struct VoiceReply {
-    timestamp: DateTime<Utc>,
-    duration: Duration,
+    transcribe_slices: bool,
 }
 
-struct VoiceController {
+pub struct VoiceController {
     last_tick_was_empty: AtomicBool,
     known_ssrcs: DashMap<u32, UserId>,
     accumulator: DashMap<u32, Slice>,
     lastTickSpeakers: Mutex<HashSet<u32>>,
-    last_reply: Mutex<Option<VoiceReply>>,
+    playback: Arc<Playback>,
+    speech_track: Mutex<Option<TrackHandle>>,
+    interruption: Mutex<Option<Interruption>>,
+    ducker: Ducker,
+}
+
+/// A TTS reply that was faded out or paused because someone talked over it.
+struct Interruption {
+    ssrc: u32,
+    started: Instant,
+    track: TrackHandle,
+    /// The fade out, which has to be stopped before the reply is resumed
+    fade: Option<AbortHandle>,
 }
 
 #[derive(Clone)]
@@ -64,6 +97,8 @@ struct Slice {
     timestamp: DateTime<Utc>,
     first_discord_timestamp: u32,
     last_discord_timestamp: u32,
+    /// Whether the bot was playing TTS or music while this slice was captured
+    bot_playback: bool,
 }
 
 fn get_discord_timestamp(data: &VoiceData) -> u32 {
@@ -76,144 +111,260 @@ fn get_discord_timestamp(data: &VoiceData) -> u32 {
     0 // Return a default value if data.packet is None
 }
 
+impl VoiceController {
+    /// Drops any audio of the user's that hasn't been saved yet.
+    pub fn forget_user(&self, user_id: u64) {
+        self.accumulator
+            .retain(|_, slice| slice.user_id != Some(user_id));
+    }
+
+    pub fn new() -> Self {
+        Self {
+            last_tick_was_empty: AtomicBool::default(),
+            known_ssrcs: DashMap::new(),
+            accumulator: DashMap::new(),
+            lastTickSpeakers: Mutex::new(HashSet::new()),
+            playback: Arc::new(Playback::default()),
+            speech_track: Mutex::new(None),
+            interruption: Mutex::new(None),
+            ducker: Ducker::new(),
+        }
+    }
+}
+
 impl Receiver {
-    pub fn new(ctx: Context, guild_id: GuildId) -> Self {
+    pub fn new(ctx: Context, session: Arc<Session>) -> Self {
         // let openai_api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not set");
         // let chat_model = env::var("MODEL").expect("MODEL not set");
-        let openai_api_key = "";
-        let chat_model = "".to_string();
+        let openai_api_key = env::var("OPENAI_API_KEY").unwrap_or_default();
+        let chat_model = env::var("MODEL").unwrap_or_default();
         let json_client = build_json_client(&openai_api_key).unwrap();
         let multipart_client = build_multipart_client(&openai_api_key).unwrap();
 
         Self {
             ctx,
-            guild_id,
+            session,
             chat_model,
             json_client,
             multipart_client,
-            controller: Arc::new(VoiceController {
-                last_tick_was_empty: AtomicBool::default(),
-                known_ssrcs: DashMap::new(),
-                accumulator: DashMap::new(),
-                last_reply: Mutex::new(None),
-                lastTickSpeakers: Mutex::new(HashSet::new()),
-            }),
+            // Slices are always recorded; only send them off for transcription
+            // when there's a key to do it with.
+            transcribe_slices: !openai_api_key.is_empty(),
         }
     }
 
     async fn process(&self, slice: &mut Slice) -> Result<(), Error> {
-        // if let Ok(mut last_reply) = self.controller.last_reply.lock() {
-        //     info!("LAST_REPLY thing is TRUE!!!!");
-        //     if let Some(reply) = last_reply.take() {
-        //         info!("\t NEXT THING is TRUE!!!!");
-        //         let elapsed = Utc::now() - reply.timestamp;
-        //         let remaining = reply.duration - elapsed;
-
-        //         if remaining > Duration::milliseconds(0) || slice.bytes.len() < 48000 {
-        //             info!("\t\t FINAL THING TRUE! It's clearing shit");
-        //             slice.timestamp = Utc::now();
-        //             slice.first_discord_timestamp = 0;
-        //             slice.last_discord_timestamp = 0;
-        //             slice.bytes.clear();
-
-        //             return Ok(());
-        //         }
-        //     }
-        // }
-
-        // let filename = format!("cache/{}_{}.wav", slice.user_id, slice.timestamp.timestamp_millis());
-        // info!(
-        //     "Saving file. slice.timestamp: [{}], slice.discord_timestamp: [{:?}]",
-        //     slice.timestamp.timestamp_millis(),
-        //     slice.first_discord_timestamp,
-        // );
+        // Catches slices started before the speaker's SSRC was known
+        if let Some(user_id) = slice.user_id {
+            if is_opted_out(&self.ctx, user_id).await {
+                slice.bytes.clear();
+                return Ok(());
+            }
+        }
+
+        let settings = guild_settings(&self.ctx, self.session.guild_id).await;
+        let segments = if settings.vad {
+            speech_segments(
+                &slice.bytes,
+                &VadConfig {
+                    threshold_db: settings.vad_threshold_db,
+                    split_silence_ms: settings.split_silence_ms,
+                    min_speech_ms: settings.min_speech_ms,
+                },
+            )
+        } else {
+            std::iter::once(0..slice.bytes.len())
+                .filter(|range| !range.is_empty())
+                .collect()
+        };
+
+        if segments.is_empty() {
+            if !slice.bytes.is_empty() {
+                info!("[{}] Dropping slice with no speech", slice.ssrc);
+                self.session.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        } else if let Some(user_id) = slice.user_id {
+            if self.session.recorded.insert(user_id) {
+                let guild_id = Some(self.session.guild_id);
+                audit(&self.ctx, user_id, guild_id, ConsentAction::Recorded).await;
+            }
+        }
+
+        for segment in segments {
+            let offset_ms = samples_to_ms(segment.start);
+            self.save_segment(slice, &slice.bytes[segment], offset_ms, &settings);
+        }
+
+        slice.timestamp = Utc::now();
+        slice.first_discord_timestamp = 0;
+        slice.bot_playback = false;
+        slice.bytes.clear();
+
+        Ok(())
+    }
+
+    /// Saves one stretch of speech from a slice, starting `offset_ms` into
+    /// it, and queues it for transcription.
+    fn save_segment(&self, slice: &Slice, pcm: &[i16], offset_ms: i64, settings: &GuildSettings) {
         let user_id_or_ssrc = if let Some(user_id) = slice.user_id {
             user_id.to_string()
         } else {
             slice.ssrc.to_string()
         };
+        let timestamp = slice.timestamp + chrono::Duration::milliseconds(offset_ms);
 
         let filename = format!(
-            "cache/{}_{}_{}.wav",
-            // slice.user_id,
-            // slice.ssrc,
+            "{}/{}_{}_{}.wav",
+            self.session.dir_name(),
             user_id_or_ssrc,
-            slice.timestamp.timestamp_millis(),
-            slice.first_discord_timestamp,
+            timestamp.timestamp_millis(),
+            // Already in milliseconds (see `get_discord_timestamp`), and wraps
+            // like the RTP timestamp it came from
+            slice.first_discord_timestamp.wrapping_add(offset_ms as u32),
         );
 
-        self.save(&slice.bytes, &filename);
+        let Some(filename) = self.save(pcm, &filename) else {
+            return;
+        };
+
+        let stats = &self.session.stats;
+        stats.slices.fetch_add(1, Ordering::Relaxed);
+        // Interleaved 48kHz stereo
+        stats
+            .speech_ms
+            .fetch_add(pcm.len() as u64 / 96, Ordering::Relaxed);
 
-        slice.timestamp = Utc::now();
-        slice.first_discord_timestamp = 0;
-        slice.bytes.clear();
+        let echo_policy = if slice.bot_playback {
+            info!("[{}] Slice overlaps bot playback", slice.ssrc);
+            settings.echo_policy
+        } else {
+            EchoPolicy::Record
+        };
+
+        if self.transcribe_slices && echo_policy != EchoPolicy::SkipTranscription {
+            self.session.queue_transcription(Transcription {
+                filename,
+                user_id: slice.user_id,
+                ssrc: slice.ssrc,
+                timestamp,
+                bot_playback: slice.bot_playback,
+                trigger: settings.voice_triggers && echo_policy == EchoPolicy::Record,
+            });
+        }
+    }
+
+    /// Works through the session's transcription queue until it's closed
+    /// and everything in it has been transcribed.
+    fn spawn_transcriber(&self, mut queue: mpsc::UnboundedReceiver<Transcription>) {
+        let receiver = self.clone();
+
+        let transcriber = tokio::spawn(async move {
+            while let Some(job) = queue.recv().await {
+                receiver
+                    .session
+                    .stats
+                    .queued
+                    .fetch_sub(1, Ordering::Relaxed);
+
+                let Some(text) = receiver
+                    .add_transcript_line(
+                        &job.filename,
+                        job.user_id,
+                        job.ssrc,
+                        job.timestamp,
+                        job.bot_playback,
+                    )
+                    .await
+                else {
+                    continue;
+                };
+
+                // Replies can take a while, so don't hold up the queue
+                if job.trigger {
+                    let receiver = receiver.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = receiver.handle_voice_command(&text, job.user_id).await {
+                            error!("Voice command error: {:?}", e);
+                        }
+                    });
+                }
+            }
+        });
+        self.session.set_transcriber(transcriber);
+    }
+
+    async fn handle_voice_command(&self, text: &str, user_id: Option<u64>) -> Result<(), Error> {
+        let text = text.to_lowercase();
+        // let mentioned = ["adam", "add", "i don't know"]
+        //     .iter()
+        //     .any(|s| text.contains(s));
+        let mentioned = text.contains("adam");
+
+        match text
+            .replace("adam", "")
+            .trim()
+            .chars()
+            .filter(|&c| c != ',' && c != '.' && c != '!')
+            .collect::<String>()
+            .as_str()
+        {
+            t if t.starts_with("play") || t.starts_with("clay") || t.starts_with("lay") => {
+                let search = t.split_whitespace().skip(1).collect::<Vec<_>>().join(" ");
+
+                info!("Searching for {}", search);
+
+                let manager = songbird::get(&self.ctx).await.unwrap().clone();
+
+                if let Some(handler_lock) = manager.get(self.session.guild_id) {
+                    let (mut youtube_dl, url) = find_song(&self.ctx, &search).await?;
+
+                    info!("Queueing {}", url);
 
-        // if let Ok(text) = self.transcribe(&filename).await {
-        //     let text = text.to_lowercase();
-        //     // let mentioned = ["adam", "add", "i don't know"]
-        //     //     .iter()
-        //     //     .any(|s| text.contains(s));
-        //     let mentioned = false;
-
-        //     match text
-        //         .replace("adam", "")
-        //         .trim()
-        //         .chars()
-        //         .filter(|&c| c != ',' && c != '.' && c != '!')
-        //         .collect::<String>()
-        //         .as_str()
-        //     {
-        //         t if t.starts_with("play") || t.starts_with("clay") || t.starts_with("lay") => {
-        //             let search = t.split_whitespace().skip(1).collect::<Vec<_>>().join(" ");
-
-        //             info!("Searching for {}", search);
-
-        //             let manager = songbird::get(&self.ctx).await.unwrap().clone();
-
-        //             if let Some(handler_lock) = manager.get(self.guild_id) {
-        //                 let mut handler = handler_lock.lock().await;
-
-        //                 let (youtube_dl, url) = find_song(&self.ctx, &search).await?;
-
-        //                 info!("Queueing {}", url);
-
-        //                 let (input, _) =
-        //                     self.gen_audio(&format!("Queueing up, {}", &search)).await?;
-        //                 let _ = handler.play_input(input).set_volume(0.5);
-
-        //                 let handle = handler.enqueue_input(youtube_dl.into()).await;
-        //                 let _ = handle.set_volume(0.05);
-        //             }
-        //         }
-        //         t if t.starts_with("stop") => {
-        //             let manager = songbird::get(&self.ctx).await.unwrap().clone();
-
-        //             if let Some(handler_lock) = manager.get(self.guild_id) {
-        //                 let mut handler = handler_lock.lock().await;
-        //                 let _ = handler.stop();
-
-        //                 let queue = handler.queue();
-        //                 queue.stop();
-
-        //                 let (input, _) = self
-        //                     .gen_audio("Just say the word and I'll be back to play some tunes")
-        //                     .await?;
-        //                 let _ = handler.play_input(input).set_volume(0.5);
-        //             }
-        //         }
-        //         t if mentioned => {
-        //             let res = self.gen_response(&t).await?;
-        //             let (input, duration) = self.gen_audio(&res).await?;
-        //             self.play_audio(input, duration).await?;
-        //         }
-        //         _ => {}
-        //     }
-        // }
+                    let input = self.gen_audio(&format!("Queueing up, {}", &search)).await?;
+                    self.play_audio(input).await?;
+
+                    let requester = user_id.map(serenity::model::id::UserId::new);
+                    let info = TrackInfo::fetch(&mut youtube_dl, &url, requester).await;
+                    enqueue(
+                        &self.ctx,
+                        self.session.guild_id,
+                        &mut *handler_lock.lock().await,
+                        youtube_dl.into(),
+                        info,
+                    )
+                    .await;
+                }
+            }
+            t if t.starts_with("stop") => {
+                let manager = songbird::get(&self.ctx).await.unwrap().clone();
+
+                if let Some(handler_lock) = manager.get(self.session.guild_id) {
+                    {
+                        let mut handler = handler_lock.lock().await;
+                        handler.stop();
+                        handler.queue().stop();
+                    }
+
+                    let input = self
+                        .gen_audio("Just say the word and I'll be back to play some tunes")
+                        .await?;
+                    self.play_audio(input).await?;
+                }
+            }
+            t if mentioned => {
+                let res = self.gen_response(t).await?;
+                let input = self.gen_audio(&res).await?;
+                self.play_audio(input).await?;
+            }
+            _ => {}
+        }
 
         Ok(())
     }
 
-    fn save(&self, pcm_samples: &[i16], filename: &str) {
+    /// Returns where the slice ended up, which has an extra extension when
+    /// recordings are encrypted.
+    fn save(&self, pcm_samples: &[i16], filename: &str) -> Option<String> {
         let spec = WavSpec {
             channels: 2,
             sample_rate: 48000,
@@ -221,23 +372,39 @@ impl Receiver {
             sample_format: SampleFormat::Int,
         };
 
-        let _ = fs::create_dir_all("cache");
-        let mut writer = WavWriter::create(filename, spec).unwrap();
+        let _ = fs::create_dir_all(&self.session.dir);
+        let mut wav = Cursor::new(Vec::new());
+        let mut writer = WavWriter::new(&mut wav, spec).unwrap();
 
         for &sample in pcm_samples {
             let _ = writer.write_sample(sample);
         }
 
         let _ = writer.finalize();
+
+        match encryption::write_file(
+            Path::new(filename),
+            wav.get_ref(),
+            self.session.key.as_ref(),
+        ) {
+            Ok(path) => Some(path.to_string_lossy().into_owned()),
+            Err(e) => {
+                error!("Failed to save {}: {:?}", filename, e);
+                None
+            }
+        }
     }
 
     async fn transcribe(&self, filename: &str) -> Result<String, Error> {
-        let file = fs::read(&filename)?;
+        let file = encryption::read_file(Path::new(filename), self.session.key.as_ref())?;
+        let name = filename
+            .strip_suffix(&format!(".{}", encryption::EXTENSION))
+            .unwrap_or(filename);
         let form = Form::new()
             .part(
                 "file",
                 Part::bytes(file)
-                    .file_name(filename.to_string())
+                    .file_name(name.to_string())
                     .mime_str("audio/wav")
                     .unwrap(),
             )
@@ -259,6 +426,71 @@ impl Receiver {
         Err(Error::msg("Failed to transcribe audio"))
     }
 
+    async fn add_transcript_line(
+        &self,
+        filename: &str,
+        user_id: Option<u64>,
+        ssrc: u32,
+        timestamp: DateTime<Utc>,
+        bot_playback: bool,
+    ) -> Option<String> {
+        let stats = &self.session.stats;
+        let text = match self.transcribe(filename).await {
+            Ok(text) => {
+                stats.transcribed.fetch_add(1, Ordering::Relaxed);
+                if text.trim().is_empty() {
+                    return None;
+                }
+                text
+            }
+            Err(e) => {
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+                error!("Transcription error [{filename}]: {:?}", e);
+                return None;
+            }
+        };
+
+        let line = TranscriptLine {
+            user_id,
+            ssrc,
+            speaker: self.display_name(user_id, ssrc),
+            timestamp,
+            text: text.clone(),
+            bot_playback,
+        };
+
+        if let Ok(mut transcript) = self.session.transcript.lock() {
+            transcript.push(line);
+        } else {
+            error!("Failed to acquire lock for transcript");
+        }
+
+        Some(text)
+    }
+
+    fn display_name(&self, user_id: Option<u64>, ssrc: u32) -> String {
+        let Some(user_id) = user_id else {
+            return format!("Unknown ({ssrc})");
+        };
+        let user_id = serenity::model::id::UserId::new(user_id);
+
+        if let Some(guild) = self.session.guild_id.to_guild_cached(&self.ctx.cache) {
+            if let Some(member) = guild.members.get(&user_id) {
+                return member.display_name().to_string();
+            }
+        }
+
+        self.ctx
+            .cache
+            .user(user_id)
+            .map(|user| {
+                user.global_name
+                    .clone()
+                    .unwrap_or_else(|| user.name.clone())
+            })
+            .unwrap_or_else(|| user_id.to_string())
+    }
+
     async fn gen_response(&self, text: &str) -> Result<String, Error> {
         let data = self
             .json_client
@@ -285,7 +517,7 @@ impl Receiver {
         Ok(res)
     }
 
-    async fn gen_audio(&self, text: &str) -> Result<(Input, u64), Error> {
+    async fn gen_audio(&self, text: &str) -> Result<Input, Error> {
         let res = self
             .json_client
             .post(format!("{OPENAI_API_URL}/audio/speech"))
@@ -303,35 +535,275 @@ impl Receiver {
 
         let bytes = res.bytes().await?;
 
-        let mut input: Input = bytes.clone().into();
+        let mut input: Input = bytes.into();
         input = input.make_playable_async(&CODEC_REGISTRY, &PROBE).await?;
 
-        let duration = (bytes.len() / 48) as u64;
-
         if !input.is_playable() {
             return Err(Error::msg("Generated audio is not playable"));
         }
 
-        Ok((input, duration))
+        Ok(input)
     }
 
-    async fn play_audio(&self, input: Input, duration: u64) -> Result<(), Error> {
+    async fn play_audio(&self, input: Input) -> Result<(), Error> {
         let manager = songbird::get(&self.ctx).await.unwrap();
 
-        if let Some(handler_lock) = manager.get(self.guild_id.clone()) {
+        if let Some(handler_lock) = manager.get(self.session.guild_id) {
             let mut handler = handler_lock.lock().await;
-            let _ = handler.play_input(input).set_volume(0.5);
+            let handle = handler.play_input(input);
+            let _ = handle.set_volume(SPEECH_VOLUME);
 
-            if let Ok(mut last_reply) = self.controller.last_reply.lock() {
-                *last_reply = Some(VoiceReply {
-                    timestamp: Utc::now(),
-                    duration: Duration::milliseconds(duration as i64),
-                });
+            self.session.controller.playback.add_speech(handle.uuid());
+            if let Ok(mut speech_track) = self.session.controller.speech_track.lock() {
+                *speech_track = Some(handle);
             }
         }
 
         Ok(())
     }
+
+    /// Lowers the music queue while anyone is talking or a reply is playing,
+    /// and restores it afterwards.
+    async fn duck(&self, speaking: bool) {
+        let ducking = speaking || self.session.controller.playback.is_speaking();
+        // Skip the settings lookup while there's nothing to duck or restore
+        if !ducking && self.session.controller.ducker.is_released() {
+            return;
+        }
+
+        let settings = guild_settings(&self.ctx, self.session.guild_id).await;
+        let depth = settings.duck_depth_pct.min(100) as f32 / 100.0;
+        let target = if ducking { 1.0 - depth } else { 1.0 };
+
+        let Some(gain) = self.session.controller.ducker.step(
+            target,
+            depth,
+            settings.duck_attack_ms,
+            settings.duck_release_ms,
+        ) else {
+            return;
+        };
+
+        let manager = songbird::get(&self.ctx).await.unwrap();
+        let Some(handler_lock) = manager.get(self.session.guild_id) else {
+            return;
+        };
+        let queue = handler_lock.lock().await.queue().clone();
+
+        if let Some(track) = queue.current() {
+            let volume = get_track_volume(&track).await;
+            let _ = track.set_volume(volume * gain);
+        }
+    }
+
+    /// Someone started talking over a TTS reply: fade it out or pause it,
+    /// depending on the guild's barge-in setting.
+    async fn interrupt(&self, ssrc: u32) {
+        if !self.session.controller.playback.is_speaking() {
+            return;
+        }
+
+        let settings = guild_settings(&self.ctx, self.session.guild_id).await;
+        if settings.barge_in == BargeIn::Off {
+            return;
+        }
+
+        let Some(track) = self
+            .session
+            .controller
+            .speech_track
+            .lock()
+            .ok()
+            .and_then(|track| track.clone())
+        else {
+            return;
+        };
+
+        let Ok(mut interruption) = self.session.controller.interruption.lock() else {
+            return;
+        };
+        if interruption.is_some() {
+            return;
+        }
+
+        info!("[{ssrc}] Interrupted speech ({:?})", settings.barge_in);
+
+        let fade = match settings.barge_in {
+            BargeIn::Fade => {
+                let track = track.clone();
+                let fade = tokio::spawn(async move {
+                    for step in (0..FADE_STEPS).rev() {
+                        let volume = SPEECH_VOLUME * step as f32 / FADE_STEPS as f32;
+                        if track.set_volume(volume).is_err() {
+                            return;
+                        }
+                        tokio::time::sleep(FADE_DURATION / FADE_STEPS).await;
+                    }
+                    let _ = track.pause();
+                });
+                Some(fade.abort_handle())
+            }
+            _ => {
+                let _ = track.pause();
+                None
+            }
+        };
+
+        *interruption = Some(Interruption {
+            ssrc,
+            started: Instant::now(),
+            track,
+            fade,
+        });
+    }
+
+    /// The speaker who interrupted a TTS reply stopped talking: resume the
+    /// reply if they only spoke briefly, otherwise drop it.
+    async fn end_interruption(&self, ssrc: u32) {
+        let interruption = match self.session.controller.interruption.lock() {
+            Ok(mut interruption) if interruption.as_ref().is_some_and(|i| i.ssrc == ssrc) => {
+                interruption.take().unwrap()
+            }
+            _ => return,
+        };
+
+        if let Some(fade) = &interruption.fade {
+            fade.abort();
+        }
+
+        let settings = guild_settings(&self.ctx, self.session.guild_id).await;
+        let resume = Duration::from_millis(settings.barge_in_resume_ms);
+
+        if interruption.started.elapsed() < resume {
+            info!("[{ssrc}] Short interruption, resuming speech");
+            let _ = interruption.track.set_volume(SPEECH_VOLUME);
+            let _ = interruption.track.play();
+        } else {
+            let _ = interruption.track.stop();
+        }
+    }
+
+    /// Adds every global event handler the call needs, including this one.
+    fn register_events(&self, handler: &mut Call) {
+        for event in [
+            CoreEvent::SpeakingStateUpdate,
+            CoreEvent::VoiceTick,
+            CoreEvent::RtpPacket,
+            CoreEvent::RtcpPacket,
+            CoreEvent::ClientDisconnect,
+            CoreEvent::DriverReconnect,
+            CoreEvent::DriverDisconnect,
+        ] {
+            handler.add_global_event(event.into(), self.clone());
+        }
+
+        handler.add_global_event(
+            Event::Track(TrackEvent::End),
+            QueueLooper {
+                ctx: self.ctx.clone(),
+                guild_id: self.session.guild_id,
+            },
+        );
+
+        for event in PLAYBACK_EVENTS {
+            handler.add_global_event(
+                Event::Track(event),
+                PlaybackMonitor(self.session.controller.playback.clone()),
+            );
+        }
+
+        handler.add_global_event(
+            Event::Periodic(SNAPSHOT_INTERVAL, None),
+            QueueSaver::new(self.ctx.clone(), self.session.guild_id),
+        );
+    }
+
+    /// Saves whatever anyone was in the middle of saying as slices.
+    async fn flush(&self) {
+        let ssrcs: Vec<u32> = self
+            .session
+            .controller
+            .accumulator
+            .iter()
+            .map(|entry| *entry.key())
+            .collect();
+
+        for ssrc in ssrcs {
+            // Taken out of the map rather than locked in it, so voice ticks
+            // aren't held up while the slice is saved
+            let Some((_, mut slice)) = self.session.controller.accumulator.remove(&ssrc) else {
+                continue;
+            };
+            if slice.bytes.is_empty() {
+                continue;
+            }
+            info!("[{}] Flushing slice", ssrc);
+            if let Err(e) = self.process(&mut slice).await {
+                error!("Processing error: {:?}", e);
+            }
+        }
+    }
+
+    /// Returns a slice taken out of the accumulator, keeping the speaker if
+    /// they were identified while it was out.
+    fn put_back(&self, mut slice: Slice) {
+        let accumulator = &self.session.controller.accumulator;
+        if let Some((_, newer)) = accumulator.remove(&slice.ssrc) {
+            slice.user_id = slice.user_id.or(newer.user_id);
+        }
+        accumulator.insert(slice.ssrc, slice);
+    }
+
+    /// Forgets everything tied to the current voice connection, since SSRCs
+    /// are only meaningful within one.
+    fn reset(&self) {
+        self.session.controller.known_ssrcs.clear();
+        self.session.controller.accumulator.clear();
+        if let Ok(mut speakers) = self.session.controller.lastTickSpeakers.lock() {
+            speakers.clear();
+        }
+        if let Ok(mut interruption) = self.session.controller.interruption.lock() {
+            *interruption = None;
+        }
+    }
+
+    /// Whether the speaker behind an SSRC has opted out of being recorded.
+    async fn is_opted_out(&self, ssrc: u32) -> bool {
+        let user_id = self.session.controller.known_ssrcs.get(&ssrc).map(|u| u.0);
+        match user_id {
+            Some(user_id) => is_opted_out(&self.ctx, user_id).await,
+            None => false,
+        }
+    }
+
+    /// Says that recording has started, if there's text to speech to say it.
+    async fn announce(&self) {
+        if !self.transcribe_slices {
+            return;
+        }
+
+        let result = match self.gen_audio(SPOKEN_ANNOUNCEMENT).await {
+            Ok(input) => self.play_audio(input).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Failed to announce recording: {:?}", e);
+        }
+    }
+
+    fn log_connection(&self, event: ConnectionEvent) {
+        if let Ok(mut transcript) = self.session.transcript.lock() {
+            transcript.log_connection(event);
+        }
+    }
+
+    fn channel_id(&self) -> Option<u64> {
+        self.session
+            .transcript
+            .lock()
+            .ok()
+            .and_then(|transcript| transcript.channel_id())
+    }
 }
 
 #[async_trait]
@@ -350,9 +822,10 @@ impl EventHandler for Receiver {
                     ssrc, user_id, delay
                 );
 
-                self.controller.known_ssrcs.insert(*ssrc, *user_id);
+                self.session.controller.known_ssrcs.insert(*ssrc, *user_id);
 
-                self.controller
+                self.session
+                    .controller
                     .accumulator
                     .entry(*ssrc)
                     .and_modify(|slice| {
@@ -365,30 +838,36 @@ impl EventHandler for Receiver {
                         timestamp: Utc::now(),
                         first_discord_timestamp: 0,
                         last_discord_timestamp: 0,
+                        bot_playback: false,
                     });
 
                 // Append the SSRC and user ID to the file
-                let file_path = "cache/ssrc_userid_map.txt";
-                let mut file = OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .open(file_path)
-                    .expect("Failed to open file");
-
+                let _ = fs::create_dir_all(&self.session.dir);
+                let file_path = self.session.dir.join("ssrc_userid_map.txt");
                 let line = format!("{}:{}\n", ssrc, user_id.0);
-                file.write_all(line.as_bytes())
-                    .expect("Failed to write to file");
+                if let Err(e) =
+                    encryption::append_file(&file_path, line.as_bytes(), self.session.key.as_ref())
+                {
+                    error!("Failed to save SSRC map: {:?}", e);
+                }
             }
             Ctx::VoiceTick(tick) => {
                 let speaking = tick.speaking.len();
                 // info!("VoiceTick: speaking: {}", speaking);
                 // let last_tick_was_empty =
-                //     self.controller.last_tick_was_empty.load(Ordering::SeqCst);
-
-                let previous_ssrcs = self.controller.lastTickSpeakers.lock().unwrap().clone();
+                //     self.session.controller.last_tick_was_empty.load(Ordering::SeqCst);
+
+                let previous_ssrcs = self
+                    .session
+                    .controller
+                    .lastTickSpeakers
+                    .lock()
+                    .unwrap()
+                    .clone();
                 let current_ssrcs: HashSet<u32> = tick.speaking.keys().copied().collect();
 
-                if let Ok(mut last_tick_speakers) = self.controller.lastTickSpeakers.lock() {
+                if let Ok(mut last_tick_speakers) = self.session.controller.lastTickSpeakers.lock()
+                {
                     *last_tick_speakers = current_ssrcs.iter().copied().collect();
                 }
 
@@ -397,7 +876,9 @@ impl EventHandler for Receiver {
 
                 for ssrc in missing_ssrcs {
                     info!("- [{}] stopped speaking, saving slice...", ssrc);
-                    if let Some(mut slice) = self.controller.accumulator.get_mut(&ssrc) {
+                    self.end_interruption(*ssrc).await;
+
+                    if let Some((_, mut slice)) = self.session.controller.accumulator.remove(ssrc) {
                         if slice.bytes.len() > 0 {
                             if let Err(e) = self.process(&mut slice).await {
                                 error!("ERROR::: Processing error: {:?}", e);
@@ -405,6 +886,7 @@ impl EventHandler for Receiver {
                         } else {
                             error!("ERROR::: VoiceTick: empty slice [{ssrc}]...");
                         }
+                        self.put_back(slice);
                     } else {
                         error!("ERROR::: VoiceTick: missing slice [{ssrc}]...");
                     }
@@ -412,10 +894,25 @@ impl EventHandler for Receiver {
 
                 for ssrc in new_ssrcs {
                     info!("+ [{}] started speaking", ssrc);
+
+                    if self.session.controller.known_ssrcs.contains_key(ssrc) {
+                        self.interrupt(*ssrc).await;
+                    }
                 }
 
+                self.duck(speaking != 0).await;
+
+                let active = speaking != 0 || self.session.controller.playback.is_active();
+                set_active(&self.ctx, self.session.guild_id, active).await;
+
                 if speaking != 0 {
+                    let bot_playback = self.session.controller.playback.is_active();
+
                     for (ssrc, data) in &tick.speaking {
+                        if self.is_opted_out(*ssrc).await {
+                            continue;
+                        }
+
                         // data.packet.
                         if let Some(decoded_voice) = data.decoded_voice.as_ref() {
                             let mut bytes = decoded_voice.to_owned();
@@ -434,7 +931,12 @@ impl EventHandler for Receiver {
 
                             let discord_timestamp = get_discord_timestamp(&data);
 
-                            if let Some(mut slice) = self.controller.accumulator.get_mut(&ssrc) {
+                            // Taken out of the map while it's worked on, since
+                            // saving it awaits and would hold up everything
+                            // else that touches the accumulator
+                            if let Some((_, mut slice)) =
+                                self.session.controller.accumulator.remove(ssrc)
+                            {
                                 // info!(
                                 //     "VoiceTick: appending bytes [{}]... length: {}",
                                 //     ssrc,
@@ -468,9 +970,6 @@ impl EventHandler for Receiver {
                                         info!("Processing error: {:?}", e);
                                     }
 
-                                    // let mut prev_slice = slice.clone();
-                                    // self.process(&mut prev_slice);
-
                                     // slice.timestamp = Utc::now();
                                     // slice.first_discord_timestamp = discord_timestamp;
                                     // slice.last_discord_timestamp = discord_timestamp;
@@ -485,6 +984,7 @@ impl EventHandler for Receiver {
                                 }
 
                                 slice.bytes.append(&mut bytes);
+                                slice.bot_playback |= bot_playback;
                                 if slice.first_discord_timestamp == 0 {
                                     // info!("\tdiscord_timestamp 0; setting timestamps [{ssrc}]");
                                     if discord_timestamp > 0 {
@@ -495,16 +995,18 @@ impl EventHandler for Receiver {
                                 if discord_timestamp > 0 {
                                     slice.last_discord_timestamp = discord_timestamp;
                                 }
-                            // } else if let Some(user_id) = self.controller.known_ssrcs.get(ssrc) {
+                                self.put_back(slice);
+                            // } else if let Some(user_id) = self.session.controller.known_ssrcs.get(ssrc) {
                             } else {
                                 let user_id = self
+                                    .session
                                     .controller
                                     .known_ssrcs
                                     .get(&ssrc)
                                     .map(|entry| entry.value().0);
                                 info!("VoiceTick: creating new slice - ssrc [{ssrc}], user id [{:?}]...", user_id);
                                 // let discord_timestamp = data.packet.as_ref().unwrap().rtp().get_timestamp().0.into();
-                                self.controller.accumulator.insert(
+                                self.session.controller.accumulator.insert(
                                     *ssrc,
                                     Slice {
                                         user_id,
@@ -513,6 +1015,7 @@ impl EventHandler for Receiver {
                                         timestamp: Utc::now(),
                                         first_discord_timestamp: discord_timestamp,
                                         last_discord_timestamp: discord_timestamp,
+                                        bot_playback,
                                     },
                                 );
                             }
@@ -525,6 +1028,21 @@ impl EventHandler for Receiver {
             Ctx::ClientDisconnect(ClientDisconnect { user_id, .. }) => {
                 info!("{:?} disconnected", user_id);
             }
+            Ctx::DriverReconnect(_) => {
+                info!("Voice connection restored");
+                self.log_connection(ConnectionEvent::Reconnected);
+            }
+            Ctx::DriverDisconnect(DisconnectData { reason, .. }) => {
+                self.flush().await;
+
+                // No reason means it was asked for, by leaving or being moved
+                if let Some(reason) = reason {
+                    warn!("Voice connection dropped: {:?}", reason);
+                    self.log_connection(ConnectionEvent::Dropped {
+                        reason: format!("{reason:?}"),
+                    });
+                }
+            }
             Ctx::RtcpPacket(data) => {
                 // An event which fires for every received rtcp packet,
                 // containing the call statistics and reporting information.
@@ -638,20 +1156,62 @@ impl Bot {
         if let Some(channel_id) = channel_id {
             info!("Joining voice channel");
 
-            ctx.set_activity(Some(ActivityData::listening("youtube music")));
+            // Checked before anything is recorded
+            if let Some(warning) = low_space_warning(ctx).await {
+                let _ = msg.channel_id.say(&ctx.http, warning).await;
+            }
 
             let manager = songbird::get(&ctx).await.unwrap().clone();
 
             if let Ok(handler_lock) = manager.join(guild_id, channel_id).await {
                 let mut handler = handler_lock.lock().await;
 
-                let receiver = Receiver::new(ctx.to_owned(), guild_id.into());
+                let sessions = get_sessions(ctx).await;
+                let existing = sessions.get(&guild_id).map(|session| session.clone());
+                let receiver = match existing {
+                    Some(session) => Receiver::new(ctx.to_owned(), session),
+                    None => {
+                        info!("Starting session");
+                        let key = get_recording_key(ctx).await;
+                        let (session, queue) = Session::new(guild_id, key);
+                        let session = Arc::new(session);
+                        sessions.insert(guild_id, session.clone());
+
+                        let receiver = Receiver::new(ctx.to_owned(), session);
+                        receiver.spawn_transcriber(queue);
+                        receiver
+                    }
+                };
+
+                receiver.log_connection(ConnectionEvent::Joined {
+                    channel_id: channel_id.get(),
+                });
+
+                // Joining again while already in a call would double up
+                handler.remove_all_global_events();
+                receiver.register_events(&mut handler);
+                drop(handler);
+
+                watch(ctx, guild_id, msg.channel_id).await;
+
+                update_status(ctx).await;
+                let _ = msg.channel_id.say(&ctx.http, ANNOUNCEMENT).await;
+                if guild_settings(ctx, guild_id).await.spoken_announcement {
+                    receiver.announce().await;
+                }
 
-                handler.add_global_event(CoreEvent::SpeakingStateUpdate.into(), receiver.clone());
-                handler.add_global_event(CoreEvent::VoiceTick.into(), receiver.clone());
-                handler.add_global_event(CoreEvent::RtpPacket.into(), receiver.clone());
-                handler.add_global_event(CoreEvent::RtcpPacket.into(), receiver.clone());
-                handler.add_global_event(CoreEvent::ClientDisconnect.into(), receiver);
+                // Only into an empty queue, so nothing already playing is
+                // pushed back or seeked
+                let queue_empty = handler_lock.lock().await.queue().is_empty();
+                if queue_empty && guild_settings(ctx, guild_id).await.auto_restore {
+                    let restore_volume = can_manage_guild(ctx, msg);
+                    if let Some((count, resume_at)) =
+                        restore_queue(ctx, guild_id, restore_volume).await
+                    {
+                        let text = format!("Restored {count} tracks, resuming {resume_at}");
+                        self.send_msg(ctx, msg, &text).await;
+                    }
+                }
             }
         }
     }
@@ -662,16 +1222,122 @@ impl Bot {
             return;
         }
 
-        ctx.set_activity(None);
+        leave_guild(ctx, msg.guild_id.unwrap(), Some(msg.channel_id)).await;
+    }
+}
+
+/// Leaves the guild's voice channel, saving its queue and finalizing its
+/// transcript. Recaps go to `notice_channel` unless a transcript channel is
+/// configured.
+pub async fn leave_guild(ctx: &Context, guild_id: GuildId, notice_channel: Option<ChannelId>) {
+    unwatch(ctx, guild_id).await;
+
+    // Stops `follow_move` from treating our own leave as a kick
+    let session = get_sessions(ctx)
+        .await
+        .remove(&guild_id)
+        .map(|(_, session)| session);
+    if let Some(session) = &session {
+        Receiver::new(ctx.clone(), session.clone()).flush().await;
+    }
+    update_status(ctx).await;
+
+    let manager = songbird::get(ctx).await.unwrap().clone();
+
+    if manager.get(guild_id).is_some() {
+        save_queue(ctx, guild_id, false).await;
+
+        info!("Leaving voice channel");
+        let _ = manager.remove(guild_id).await;
+    }
+
+    if let Some(session) = session {
+        // Slices that were just flushed still need transcribing
+        let limit = if is_shutting_down(ctx).await {
+            DRAIN_TIMEOUT
+        } else {
+            TRANSCRIPTION_DRAIN_TIMEOUT
+        };
+        session.finish_transcriptions(limit).await;
+
+        let transcript = match session.transcript.lock() {
+            Ok(mut transcript) => {
+                transcript.ended = Some(Utc::now());
+                transcript.clone()
+            }
+            Err(poisoned) => {
+                error!("Transcript lock was poisoned, saving it anyway");
+                let mut transcript = poisoned.into_inner();
+                transcript.ended = Some(Utc::now());
+                transcript.clone()
+            }
+        };
+
+        // The connection events are kept even if nothing was transcribed
+        if !transcript.is_empty() || !transcript.connection.is_empty() {
+            transcript.save(&session.dir_name(), session.key.as_ref());
+        }
+
+        if !transcript.is_empty() {
+            transcript.post(ctx).await;
+
+            let recap_channel = transcript_channel().or(notice_channel);
+            // Awaited rather than spawned, so shutting down doesn't drop it
+            let auto_recap = env::var("AUTO_RECAP")
+                .is_ok_and(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"));
+            if let (true, Some(channel_id)) = (auto_recap, recap_channel) {
+                match Summarizer::new().recap(&transcript).await {
+                    Ok(recap) => post_recap(ctx, channel_id, &recap).await,
+                    Err(e) => error!("Recap error: {:?}", e),
+                }
+            }
+        }
+    }
 
-        let guild_id = msg.guild_id.unwrap();
-        let manager = songbird::get(&ctx).await.unwrap().clone();
+    enforce(ctx).await;
+}
 
-        if manager.get(guild_id).is_some() {
-            info!("Leaving voice channel");
-            let _ = manager.remove(guild_id).await;
+/// Keeps the session in step with the bot's own voice state: a move to
+/// another channel starts a new segment, and being disconnected ends the
+/// session.
+pub async fn follow_move(ctx: &Context, guild_id: GuildId, channel_id: Option<ChannelId>) {
+    let Some(session) = get_session(ctx, guild_id).await else {
+        return;
+    };
+    let receiver = Receiver::new(ctx.clone(), session);
+
+    let Some(channel_id) = channel_id else {
+        info!("Disconnected from voice by someone else");
+        receiver.log_connection(ConnectionEvent::Disconnected);
+
+        let notice_channel = notice_channel(ctx, guild_id).await;
+        if let Some(notice_channel) = notice_channel {
+            let _ = notice_channel
+                .say(
+                    &ctx.http,
+                    "I got disconnected, so that's the end of the session.",
+                )
+                .await;
         }
+        leave_guild(ctx, guild_id, notice_channel).await;
+        return;
+    };
+
+    if receiver.channel_id() == Some(channel_id.get()) {
+        return;
+    }
 
-        // let _ = fs::remove_dir_all("cache");
+    info!("Moved to voice channel {}", channel_id);
+    receiver.flush().await;
+    receiver.reset();
+    receiver.log_connection(ConnectionEvent::Moved {
+        channel_id: channel_id.get(),
+    });
+
+    let manager = songbird::get(ctx).await.unwrap().clone();
+    if let Some(call) = manager.get(guild_id) {
+        let mut handler = call.lock().await;
+        handler.remove_all_global_events();
+        receiver.register_events(&mut handler);
     }
 }